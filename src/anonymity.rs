@@ -0,0 +1,90 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use std::fmt;
+use std::str::FromStr;
+
+// headers a proxy adds to tell on itself or on its client
+const PROXY_HEADERS: [&'static str; 5] = ["Via",
+                                          "X-Forwarded-For",
+                                          "Forwarded",
+                                          "X-Real-IP",
+                                          "Proxy-Connection"];
+
+/// How much an HTTP proxy gives away about its clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anonymity {
+    // passes on the address of its client
+    Transparent,
+    // hides its client, but not that it is a proxy
+    Anonymous,
+    // looks like a client itself
+    Elite,
+}
+
+impl Anonymity {
+    /// Classifies a proxy by the headers it passed on to the destination.
+    /// `gateway` are the public addresses we are seen to come from.
+    pub fn classify<'a, I>(headers: I, gateway: &[String]) -> Anonymity
+        where I: IntoIterator<Item = (&'a str, &'a str)>
+    {
+        let mut anonymity = Anonymity::Elite;
+        for (name, value) in headers {
+            if !PROXY_HEADERS.iter().any(|x| x.to_lowercase() == name.to_lowercase()) {
+                continue;
+            }
+            if gateway.iter().any(|ip| value.contains(ip.as_str())) {
+                return Anonymity::Transparent;
+            }
+            anonymity = Anonymity::Anonymous;
+        }
+        anonymity
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Anonymity::Transparent => "transparent",
+            Anonymity::Anonymous => "anonymous",
+            Anonymity::Elite => "elite",
+        }
+    }
+}
+
+impl fmt::Display for Anonymity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Anonymity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Anonymity, String> {
+        match s {
+            "transparent" => Ok(Anonymity::Transparent),
+            "anonymous" => Ok(Anonymity::Anonymous),
+            "elite" => Ok(Anonymity::Elite),
+            _ => Err(format!("unknown anonymity level: {}", s)),
+        }
+    }
+}
+
+#[test]
+fn test_anonymity() {
+    let gateway = vec!["203.0.113.7".to_string()];
+    let classify = |headers: &[(&'static str, &'static str)]| {
+        Anonymity::classify(headers.iter().cloned(), &gateway)
+    };
+    assert_eq!(classify(&[("Host", "httpbin.org"), ("User-Agent", "mole")]),
+               Anonymity::Elite);
+    assert_eq!(classify(&[("Host", "httpbin.org"), ("Via", "1.1 squid")]),
+               Anonymity::Anonymous);
+    assert_eq!(classify(&[("proxy-connection", "keep-alive")]), Anonymity::Anonymous);
+    assert_eq!(classify(&[("Via", "1.1 squid"), ("X-Forwarded-For", "203.0.113.7")]),
+               Anonymity::Transparent);
+    assert_eq!(classify(&[("Forwarded", "for=203.0.113.7;proto=http")]),
+               Anonymity::Transparent);
+    // only headers proxies add count
+    assert_eq!(classify(&[("Referer", "http://203.0.113.7/")]), Anonymity::Elite);
+    assert_eq!("elite".parse::<Anonymity>(), Ok(Anonymity::Elite));
+    assert!("vanilla".parse::<Anonymity>().is_err());
+}
@@ -5,7 +5,9 @@ use nickel::{Nickel, QueryString};
 use serde_json;
 use serde_json::value::{ToJson, Value};
 
+use anonymity::Anonymity;
 use db_api;
+use ports;
 
 pub fn run_api(opts: ArgMatches) {
     let bind_address = opts.value_of("bind")
@@ -25,13 +27,26 @@ pub fn run_api(opts: ArgMatches) {
                 Some(x) => x.split(",").collect::<Vec<&str>>(),
                 None => Vec::new()
             };
-            match db_api::search_proxy_servers(conn, lag, tags) {
-                Ok(servers) => {
+            let anonymity = match req.query().get("anonymity") {
+                Some(x) => x.parse::<Anonymity>().map(Some),
+                None => Ok(None)
+            };
+            // servers able to CONNECT to every one of these ports
+            let connect_ports = match req.query().get("connect_ports") {
+                Some(x) => ports::parse(x).map_err(|e| e.to_string()),
+                None => Ok(Vec::new())
+            };
+            let search = |(x, y)| db_api::search_proxy_servers(conn, lag, tags, x, y);
+            match anonymity.and_then(|x| connect_ports.map(|y| (x, y))).map(search) {
+                Ok(Ok(servers)) => {
                     let v = servers.iter().map(|x| x.to_json()).collect::<Vec<Value>>();
                     serde_json::to_string(&v).unwrap()
                 },
-                Err(e) => {
+                Ok(Err(e)) => {
                     format!("error: {:?}", e)
+                },
+                Err(e) => {
+                    format!("error: {}", e)
                 }
             }
         }
@@ -0,0 +1,173 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 18 Oct, 2016
+
+use serde_json::{self, Value};
+use serde_json::value::ToJson;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
+
+use iprange::Ipv4RangeSet;
+
+/// Everything needed to pick up an interrupted scan: the target space, the
+/// order it is visited in, how far the scan got and the options it ran with.
+#[derive(Debug, PartialEq)]
+pub struct Checkpoint {
+    pub ranges: Vec<(u32, u32)>,
+    pub ports: Vec<u16>,
+    // hosts scanned on their own ports only
+    pub services: Vec<(u32, u16)>,
+    // targets given by name, with the address they resolved to
+    pub hostnames: Vec<(u32, u16, String)>,
+    pub seed: u64,
+    // every target before this position in the scan order is done
+    pub position: u64,
+    pub options: BTreeMap<String, String>,
+}
+
+fn malformed(what: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData,
+                 format!("malformed checkpoint: {}", what).as_str())
+}
+
+impl Checkpoint {
+    pub fn new(hosts: &Ipv4RangeSet,
+               ports: Vec<u16>,
+               services: Vec<(u32, u16)>,
+               hostnames: Vec<(u32, u16, String)>,
+               seed: u64,
+               options: BTreeMap<String, String>)
+               -> Checkpoint {
+        Checkpoint {
+            ranges: hosts.ranges().to_vec(),
+            ports: ports,
+            services: services,
+            hostnames: hostnames,
+            seed: seed,
+            position: 0,
+            options: options,
+        }
+    }
+
+    pub fn hosts(&self) -> Ipv4RangeSet {
+        let mut hosts = Ipv4RangeSet::new();
+        for &(first, last) in &self.ranges {
+            hosts.insert(first, last);
+        }
+        hosts
+    }
+
+    pub fn from_str(content: &str) -> IoResult<Checkpoint> {
+        let data: Value = try!(serde_json::from_str(content).map_err(|_| malformed("not json")));
+        let mut ranges = Vec::new();
+        for x in try!(data.find("ranges").and_then(|x| x.as_array()).ok_or(malformed("ranges"))) {
+            let range = try!(x.as_array()
+                .and_then(|x| match (x.get(0).and_then(|x| x.as_u64()),
+                                     x.get(1).and_then(|x| x.as_u64())) {
+                    (Some(first), Some(last)) => Some((first as u32, last as u32)),
+                    _ => None,
+                })
+                .ok_or(malformed("ranges")));
+            ranges.push(range);
+        }
+        let mut ports = Vec::new();
+        for x in try!(data.find("ports").and_then(|x| x.as_array()).ok_or(malformed("ports"))) {
+            ports.push(try!(x.as_u64().ok_or(malformed("ports"))) as u16);
+        }
+        let mut services = Vec::new();
+        for x in try!(data.find("services")
+            .and_then(|x| x.as_array())
+            .ok_or(malformed("services"))) {
+            let service = try!(x.as_array()
+                .and_then(|x| match (x.get(0).and_then(|x| x.as_u64()),
+                                     x.get(1).and_then(|x| x.as_u64())) {
+                    (Some(ip), Some(port)) => Some((ip as u32, port as u16)),
+                    _ => None,
+                })
+                .ok_or(malformed("services")));
+            services.push(service);
+        }
+        let mut hostnames = Vec::new();
+        for x in try!(data.find("hostnames")
+            .and_then(|x| x.as_array())
+            .ok_or(malformed("hostnames"))) {
+            let hostname = try!(x.as_array()
+                .and_then(|x| match (x.get(0).and_then(|x| x.as_u64()),
+                                     x.get(1).and_then(|x| x.as_u64()),
+                                     x.get(2).and_then(|x| x.as_str())) {
+                    (Some(ip), Some(port), Some(name)) => {
+                        Some((ip as u32, port as u16, name.to_string()))
+                    }
+                    _ => None,
+                })
+                .ok_or(malformed("hostnames")));
+            hostnames.push(hostname);
+        }
+        let mut options = BTreeMap::new();
+        for (k, v) in try!(data.find("options").and_then(|x| x.as_object()).ok_or(malformed("options"))) {
+            options.insert(k.clone(), try!(v.as_str().ok_or(malformed("options"))).to_string());
+        }
+        Ok(Checkpoint {
+            ranges: ranges,
+            ports: ports,
+            services: services,
+            hostnames: hostnames,
+            seed: try!(data.find("seed").and_then(|x| x.as_u64()).ok_or(malformed("seed"))),
+            position: try!(data.find("position").and_then(|x| x.as_u64()).ok_or(malformed("position"))),
+            options: options,
+        })
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut map = BTreeMap::new();
+        map.insert("ranges".to_string(), self.ranges.to_json());
+        map.insert("ports".to_string(), self.ports.to_json());
+        map.insert("services".to_string(), self.services.to_json());
+        map.insert("hostnames".to_string(), self.hostnames.to_json());
+        map.insert("seed".to_string(), self.seed.to_json());
+        map.insert("position".to_string(), self.position.to_json());
+        map.insert("options".to_string(), self.options.to_json());
+        Value::Object(map).to_string()
+    }
+
+    pub fn load(path: &str) -> IoResult<Checkpoint> {
+        let mut file = try!(File::open(path));
+        let mut content = String::new();
+        try!(file.read_to_string(&mut content));
+        Checkpoint::from_str(&content)
+    }
+
+    /// Writes the checkpoint next to `path` first, so that an interruption
+    /// never leaves a truncated checkpoint behind.
+    pub fn save(&self, path: &str) -> IoResult<()> {
+        let temp = format!("{}.tmp", path);
+        {
+            let mut file = try!(File::create(&temp));
+            try!(file.write_all(self.to_string().as_bytes()));
+            try!(file.sync_all());
+        }
+        fs::rename(&temp, path)
+    }
+}
+
+#[test]
+fn test_checkpoint_roundtrip() {
+    let mut hosts = Ipv4RangeSet::new();
+    hosts.insert(10, 20);
+    hosts.insert(4294967290, 4294967295);
+    let mut options = BTreeMap::new();
+    options.insert("timeout".to_string(), "15".to_string());
+    let mut checkpoint = Checkpoint::new(&hosts,
+                                         vec![3128, 8080],
+                                         vec![(30, 1080)],
+                                         vec![(30, 1080, "proxy.example.net".to_string())],
+                                         18446744073709551615,
+                                         options);
+    checkpoint.position = 42;
+    let loaded = Checkpoint::from_str(&checkpoint.to_string()).unwrap();
+    assert_eq!(loaded, checkpoint);
+    assert_eq!(loaded.hosts(), hosts);
+    assert!(Checkpoint::from_str("{\"seed\": 1}").is_err());
+    let incomplete = checkpoint.to_string().replace("\"services\"", "\"unknown\"");
+    assert!(Checkpoint::from_str(&incomplete).is_err());
+}
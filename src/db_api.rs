@@ -11,19 +11,31 @@ use std::str::FromStr;
 use std::time::Duration;
 use chrono::DateTime;
 use chrono::offset::local::Local;
+use anonymity::Anonymity;
 use errors::*;
+use resolver;
 
 pub type Pool = r2d2::Pool<PostgresConnectionManager>;
 type Connection = r2d2::PooledConnection<PostgresConnectionManager>;
 
 #[derive(Debug)]
 pub struct ProxyServer {
-    pub host: Ipv4Addr,
+    // an ip address or a hostname, as the server was found or imported
+    pub host: String,
+    // the address `host` resolved to when the server was last verified
+    pub address: Option<Ipv4Addr>,
     pub port: u16,
     pub lag: Option<Duration>,
-    pub vanilla: Option<bool>,
-    pub traceable: Option<bool>,
+    // how anonymous the server is, if it is an HTTP proxy
+    pub anonymity: Option<Anonymity>,
     pub tags: Option<Vec<String>>,
+    // user:password the server has accepted
+    pub credentials: Option<String>,
+    // the proxy software the server runs, and its version
+    pub software: Option<String>,
+    pub software_version: Option<String>,
+    // ports the server CONNECTs to, if tested
+    pub connect_ports: Option<Vec<u16>>,
     pub created_at: DateTime<Local>,
     pub updated_at: DateTime<Local>,
 }
@@ -32,19 +44,24 @@ impl ProxyServer {
     pub fn new(host: &str,
                port: u16,
                lag: Option<Duration>,
-               vanilla: Option<bool>,
-               traceable: Option<bool>,
+               anonymity: Option<Anonymity>,
                tags: Option<Vec<String>>)
                -> Result<ProxyServer> {
-        let host = try!(Ipv4Addr::from_str(host)
-                        .chain_err(|| ErrorKind::InvalidIpv4Address(host.to_string())));
+        let address = Ipv4Addr::from_str(host).ok();
+        if address.is_none() && !resolver::is_hostname(host) {
+            return Err(ErrorKind::InvalidHost(host.to_string()).into());
+        }
         Ok(ProxyServer {
-            host: host,
+            host: host.to_string(),
+            address: address,
             port: port,
             lag: lag,
             tags: tags,
-            vanilla: vanilla,
-            traceable: traceable,
+            anonymity: anonymity,
+            credentials: None,
+            software: None,
+            software_version: None,
+            connect_ports: None,
             created_at: Local::now(),
             updated_at: Local::now(),
         })
@@ -62,11 +79,11 @@ impl ToJson for ProxyServer {
         let mut map = BTreeMap::new();
         map.insert("host".to_string(), self.host.to_json());
         map.insert("port".to_string(), self.port.to_json());
-        if let Some(vanilla) = self.vanilla {
-            map.insert("vanilla".to_string(), vanilla.to_json());
+        if let Some(address) = self.address {
+            map.insert("address".to_string(), address.to_string().to_json());
         }
-        if let Some(traceable) = self.traceable {
-            map.insert("traceable".to_string(), traceable.to_json());
+        if let Some(anonymity) = self.anonymity {
+            map.insert("anonymity".to_string(), anonymity.as_str().to_json());
         }
         if let Some(lag) = self.lag {
             map.insert("lag".to_string(), lag.as_secs().to_json());
@@ -74,6 +91,17 @@ impl ToJson for ProxyServer {
         if let &Some(ref tags) = &self.tags {
             map.insert("tags".to_string(), tags.to_json());
         }
+        // the credentials themselves are secret, and this goes out through the api
+        map.insert("has_credentials".to_string(), self.credentials.is_some().to_json());
+        if let &Some(ref software) = &self.software {
+            map.insert("software".to_string(), software.to_json());
+        }
+        if let &Some(ref software_version) = &self.software_version {
+            map.insert("software_version".to_string(), software_version.to_json());
+        }
+        if let &Some(ref connect_ports) = &self.connect_ports {
+            map.insert("connect_ports".to_string(), connect_ports.to_json());
+        }
         map.insert("created_at".to_string(), self.created_at.to_json());
         map.insert("updated_at".to_string(), self.updated_at.to_json());
         Value::Object(map)
@@ -88,25 +116,62 @@ pub fn init_db(name: &str) -> Result<Pool> {
 }
 
 pub fn init_table(db: Connection) -> Result<u64> {
-    db.execute("CREATE TABLE IF NOT EXISTS proxy_servers (id SERIAL PRIMARY KEY, host VARCHAR \
-                  NOT NULL, port INT NOT NULL, lag INT, vanilla BOOL, traceable BOOL, tags \
+    try!(db.execute("CREATE TABLE IF NOT EXISTS do_not_scan (network VARCHAR PRIMARY KEY, \
+                      created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW())",
+                    &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot create table".to_string())));
+    // CREATE TYPE has no IF NOT EXISTS
+    try!(db.execute("DO $$ BEGIN CREATE TYPE anonymity AS ENUM ('transparent', 'anonymous', \
+                     'elite'); EXCEPTION WHEN duplicate_object THEN NULL; END $$",
+                    &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot create type".to_string())));
+    try!(db.execute("CREATE TABLE IF NOT EXISTS proxy_servers (id SERIAL PRIMARY KEY, host VARCHAR \
+                  NOT NULL, port INT NOT NULL, lag INT, anonymity anonymity, tags \
                   VARCHAR[], created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(), updated_at \
-                  TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+                  TIMESTAMP WITH TIME ZONE DEFAULT NOW(), address VARCHAR, credentials VARCHAR,
+                software VARCHAR, software_version VARCHAR, connect_ports INT[], \
                 UNIQUE(host, port))",
                  &[])
-        .chain_err(|| ErrorKind::DatabaseError("cannot create table".to_string()))
+        .chain_err(|| ErrorKind::DatabaseError("cannot create table".to_string())));
+    try!(db.execute("ALTER TABLE proxy_servers ADD COLUMN IF NOT EXISTS address VARCHAR",
+                    &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot upgrade table".to_string())));
+    try!(db.execute("ALTER TABLE proxy_servers ADD COLUMN IF NOT EXISTS credentials VARCHAR",
+                    &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot upgrade table".to_string())));
+    try!(db.execute("ALTER TABLE proxy_servers ADD COLUMN IF NOT EXISTS anonymity anonymity",
+                    &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot upgrade table".to_string())));
+    try!(db.execute("ALTER TABLE proxy_servers ADD COLUMN IF NOT EXISTS software VARCHAR",
+                    &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot upgrade table".to_string())));
+    try!(db.execute("ALTER TABLE proxy_servers ADD COLUMN IF NOT EXISTS software_version VARCHAR",
+                    &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot upgrade table".to_string())));
+    db.execute("ALTER TABLE proxy_servers ADD COLUMN IF NOT EXISTS connect_ports INT[]",
+               &[])
+        .chain_err(|| ErrorKind::DatabaseError("cannot upgrade table".to_string()))
 }
 
 pub fn add_proxy(conn: Connection, server: ProxyServer) -> Result<u64> {
-    let host = server.host.to_string();
+    let host = server.host.clone();
+    let address = server.address.map(|x| x.to_string());
     let port = server.port as i32;
     let lag = match server.lag {
         Some(lag) => Some(lag.as_secs() as i32),
         _ => None,
     };
-    match conn.execute("INSERT INTO proxy_servers(host, port, lag, vanilla, traceable, tags) \
-                        VALUES($1, $2, $3, $4, $5, $6)",
-                       &[&host, &port, &lag, &server.vanilla, &server.traceable, &server.tags]) {
+    // the enum goes through VARCHAR, which is what the driver knows to send
+    let anonymity = server.anonymity.map(|x| x.as_str());
+    let connect_ports = server.connect_ports
+        .as_ref()
+        .map(|x| x.iter().map(|&port| port as i32).collect::<Vec<i32>>());
+    match conn.execute("INSERT INTO proxy_servers(host, port, lag, anonymity, tags, address, \
+                        credentials, software, software_version, connect_ports) VALUES($1, $2, \
+                        $3, $4::VARCHAR::anonymity, $5, $6, $7, $8, $9, $10)",
+                       &[&host, &port, &lag, &anonymity, &server.tags, &address,
+                         &server.credentials, &server.software, &server.software_version,
+                         &connect_ports]) {
         Ok(n) => {
             info!("server {} inserted.", server);
             Ok(n)
@@ -114,12 +179,16 @@ pub fn add_proxy(conn: Connection, server: ProxyServer) -> Result<u64> {
         Err(error::Error::Db(ref error)) if error.code == error::SqlState::UniqueViolation => {
             // Try update
             let rows = try!(
-                conn.execute("UPDATE proxy_servers SET lag=$3, vanilla=$4, \
-                              traceable=$5, tags=$6, updated_at=NOW() \
-                              WHERE host=$1 AND port=$2",
-                    &[&host, &port, &lag,
-                      &server.vanilla, &server.traceable,
-                      &server.tags])
+                conn.execute("UPDATE proxy_servers SET lag=$3, \
+                              anonymity=$4::VARCHAR::anonymity, tags=$5, \
+                              address=COALESCE($6, address), \
+                              credentials=$7, \
+                              software=$8, software_version=$9, \
+                              connect_ports=COALESCE($10, connect_ports), \
+                              updated_at=NOW() WHERE host=$1 AND port=$2",
+                    &[&host, &port, &lag, &anonymity,
+                      &server.tags, &address, &server.credentials,
+                      &server.software, &server.software_version, &connect_ports])
                     .chain_err(|| ErrorKind::SQLStatementError("cannot update proxy server".to_string()))
             );
             info!("server {} renewed.", server);
@@ -130,7 +199,7 @@ pub fn add_proxy(conn: Connection, server: ProxyServer) -> Result<u64> {
 }
 
 pub fn disable_proxy(conn: Connection, server: ProxyServer) -> Result<u64> {
-    let host = server.host.to_string();
+    let host = server.host;
     let port = server.port as i32;
     match conn.execute("UPDATE proxy_servers SET lag=NULL WHERE host=$1 AND port=$2",
                        &[&host, &port]) {
@@ -139,31 +208,60 @@ pub fn disable_proxy(conn: Connection, server: ProxyServer) -> Result<u64> {
     }
 }
 
+pub fn add_exclusion(conn: Connection, network: &str) -> Result<u64> {
+    match conn.execute("INSERT INTO do_not_scan(network) VALUES($1)", &[&network]) {
+        Ok(n) => Ok(n),
+        Err(error::Error::Db(ref error)) if error.code == error::SqlState::UniqueViolation => Ok(0),
+        Err(e) => Err(ErrorKind::DatabaseError(e.to_string()).into()),
+    }
+}
+
+pub fn delete_exclusion(conn: Connection, network: &str) -> Result<u64> {
+    match conn.execute("DELETE FROM do_not_scan WHERE network=$1", &[&network]) {
+        Ok(n) => Ok(n),
+        Err(e) => Err(ErrorKind::DatabaseError(e.to_string()).into()),
+    }
+}
+
+pub fn get_exclusions(db: Connection) -> Result<Vec<String>> {
+    let stmt = try!(db.prepare("SELECT network FROM do_not_scan ORDER BY network")
+                    .chain_err(|| "SQL error"));
+    let rows = try!(stmt.query(&[])
+                    .chain_err(|| ErrorKind::SQLStatementError("cannot query exclusions".to_string())));
+    let networks = rows.into_iter().map(|row| row.get(0)).collect::<Vec<String>>();
+    Ok(networks)
+}
+
 pub fn get_proxy_servers(db: Connection) -> Result<Vec<ProxyServer>> {
     let mut servers = Vec::new();
-    let stmt = try!(db.prepare("SELECT host, port, lag, vanilla, traceable, tags, \
-                                created_at, updated_at FROM proxy_servers")
+    let stmt = try!(db.prepare("SELECT host, port, lag, anonymity::VARCHAR, tags, created_at, \
+                                updated_at, address, credentials, software, software_version, \
+                                connect_ports FROM proxy_servers")
                     .chain_err(|| "SQL error"));
     if let Ok(rows) = stmt.query(&[]) {
         for row in rows.into_iter() {
             let host: String = row.get(0);
             let port: i32 = row.get(1);
-            let ip = match Ipv4Addr::from_str(host.as_str()) {
-                Ok(ip) => ip,
-                _ => continue,
-            };
+            let address = row.get::<_, Option<String>>(7)
+                .and_then(|x| Ipv4Addr::from_str(&x).ok())
+                .or(Ipv4Addr::from_str(&host).ok());
             servers.push(ProxyServer {
-                host: ip,
+                host: host,
+                address: address,
                 port: port as u16,
                 lag: match row.get::<_, Option<i32>>(2) {
                     Some(x) => Some(Duration::new(x as u64, 0)),
                     _ => None,
                 },
-                vanilla: row.get(3),
-                traceable: row.get(4),
-                tags: row.get(5),
-                created_at: row.get(6),
-                updated_at: row.get(7),
+                anonymity: row.get::<_, Option<String>>(3).and_then(|x| x.parse().ok()),
+                tags: row.get(4),
+                credentials: row.get(8),
+                software: row.get(9),
+                software_version: row.get(10),
+                connect_ports: row.get::<_, Option<Vec<i32>>>(11)
+                    .map(|x| x.into_iter().map(|port| port as u16).collect()),
+                created_at: row.get(5),
+                updated_at: row.get(6),
             });
         }
     }
@@ -172,35 +270,47 @@ pub fn get_proxy_servers(db: Connection) -> Result<Vec<ProxyServer>> {
 
 pub fn search_proxy_servers(db: Connection,
                             max_lag: Option<i32>,
-                            tags: Vec<&str>)
+                            tags: Vec<&str>,
+                            anonymity: Option<Anonymity>,
+                            connect_ports: Vec<u16>)
                             -> Result<Vec<ProxyServer>> {
     let mut servers = Vec::new();
     let stmt =
-        try!(db.prepare("SELECT host, port, lag, vanilla, traceable, tags, created_at, updated_at \
-                         FROM proxy_servers WHERE lag < $1 AND tags @> $2::VARCHAR[] \
+        try!(db.prepare("SELECT host, port, lag, anonymity::VARCHAR, tags, created_at, updated_at, \
+                         address, credentials, software, software_version, connect_ports \
+                         FROM proxy_servers \
+                         WHERE lag < $1 AND tags @> $2::VARCHAR[] \
+                         AND ($3::VARCHAR IS NULL OR anonymity = $3::VARCHAR::anonymity) \
+                         AND (CARDINALITY($4::INT[]) = 0 OR connect_ports @> $4::INT[]) \
                          ORDER BY updated_at, lag")
         .chain_err(|| "SQL Error"));
     let lag = if let Some(x) = max_lag { x } else { 9999 };
-    if let Ok(rows) = stmt.query(&[&lag, &tags]) {
+    let anonymity = anonymity.map(|x| x.as_str());
+    let connect_ports = connect_ports.into_iter().map(|x| x as i32).collect::<Vec<i32>>();
+    if let Ok(rows) = stmt.query(&[&lag, &tags, &anonymity, &connect_ports]) {
         for row in rows.into_iter() {
             let host: String = row.get(0);
             let port: i32 = row.get(1);
-            let ip = match Ipv4Addr::from_str(host.as_str()) {
-                Ok(ip) => ip,
-                _ => continue,
-            };
+            let address = row.get::<_, Option<String>>(7)
+                .and_then(|x| Ipv4Addr::from_str(&x).ok())
+                .or(Ipv4Addr::from_str(&host).ok());
             servers.push(ProxyServer {
-                host: ip,
+                host: host,
+                address: address,
                 port: port as u16,
                 lag: match row.get::<_, Option<i32>>(2) {
                     Some(x) => Some(Duration::new(x as u64, 0)),
                     _ => None,
                 },
-                vanilla: row.get(3),
-                traceable: row.get(4),
-                tags: row.get(5),
-                created_at: row.get(6),
-                updated_at: row.get(7),
+                anonymity: row.get::<_, Option<String>>(3).and_then(|x| x.parse().ok()),
+                tags: row.get(4),
+                credentials: row.get(8),
+                software: row.get(9),
+                software_version: row.get(10),
+                connect_ports: row.get::<_, Option<Vec<i32>>>(11)
+                    .map(|x| x.into_iter().map(|port| port as u16).collect()),
+                created_at: row.get(5),
+                updated_at: row.get(6),
             });
         }
     }
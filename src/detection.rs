@@ -1,8 +1,12 @@
 // Jianing Yang <jianingy.yang@gmail.com> @ 22 Sep, 2016
+use encoding::EncoderTrap;
+use encoding::label::encoding_from_whatwg_label;
 use regex::Regex;
 use std::io::Result as IoResult;
 use std::vec;
 
+use http::{self, Response};
+
 lazy_static! {
     static ref RE_DOC_DELIMITER: Regex  = Regex::new("--+\n").unwrap();
 }
@@ -26,3 +30,32 @@ pub fn rules() -> IoResult<vec::IntoIter<(String, String, String)>> {
 
     Ok(docs.into_iter())
 }
+
+/// Whether `resp` is a successful answer containing `needle`, either in its
+/// status line as proxies answering CONNECT put it, or in its body. The body
+/// is searched for `needle` as encoded in the charset of the response.
+pub fn matches(resp: &Response, needle: &str) -> bool {
+    resp.is_success() &&
+    (resp.reason.contains(needle) ||
+     http::find(&resp.body, &encode(needle, resp.charset())).is_some())
+}
+
+// falls back to utf-8 for unknown charsets
+fn encode(text: &str, charset: Option<String>) -> Vec<u8> {
+    charset.and_then(|x| encoding_from_whatwg_label(&x))
+        .and_then(|encoding| encoding.encode(text, EncoderTrap::Strict).ok())
+        .unwrap_or(text.as_bytes().to_vec())
+}
+
+#[test]
+fn test_matches() {
+    let resp = Response::parse(b"HTTP/1.0 200 Tunnel established\r\n\r\n").unwrap();
+    assert!(matches(&resp, "Tunnel established"));
+    let resp = Response::parse(b"HTTP/1.0 502 Bad Gateway\r\n\r\nTunnel established").unwrap();
+    assert!(!matches(&resp, "Tunnel established"));
+    let resp = Response::parse(b"HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=gbk\r\n\r\n\
+                                 <title>\xb0\xd9\xb6\xc8\xd2\xbb\xcf\xc2\xa3\xac\xc4\xe3\xbe\xcd\
+                                 \xd6\xaa\xb5\xc0</title>")
+        .unwrap();
+    assert!(matches(&resp, "百度一下，你就知道"));
+}
@@ -0,0 +1,152 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use mio;
+use net2::TcpBuilder;
+use std::cmp;
+use std::io::{Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{self, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+// source ports tried before giving up on a connection
+const MAX_BIND_ATTEMPTS: usize = 16;
+
+/// The local addresses probes are sent from. Connections take turns on
+/// `ips` and `ports`; when either is empty the kernel picks it instead.
+#[derive(Debug, Clone)]
+pub struct Egress {
+    ips: Vec<Ipv4Addr>,
+    ports: Vec<u16>,
+    next_ip: usize,
+    next_port: usize,
+}
+
+impl Egress {
+    pub fn new(ips: Vec<Ipv4Addr>, ports: Vec<u16>) -> Egress {
+        Egress {
+            ips: ips,
+            ports: ports,
+            next_ip: 0,
+            next_port: 0,
+        }
+    }
+
+    pub fn ips(&self) -> &[Ipv4Addr] {
+        &self.ips
+    }
+
+    fn is_default(&self) -> bool {
+        self.ips.is_empty() && self.ports.is_empty()
+    }
+
+    fn next_ip(&mut self) -> Ipv4Addr {
+        if self.ips.is_empty() {
+            return Ipv4Addr::new(0, 0, 0, 0);
+        }
+        let ip = self.ips[self.next_ip % self.ips.len()];
+        self.next_ip = self.next_ip.wrapping_add(1);
+        ip
+    }
+
+    fn next_port(&mut self) -> u16 {
+        if self.ports.is_empty() {
+            return 0;
+        }
+        let port = self.ports[self.next_port % self.ports.len()];
+        self.next_port = self.next_port.wrapping_add(1);
+        port
+    }
+
+    // a socket bound to the next source address, skipping ports in use
+    fn bind(&mut self) -> IoResult<TcpBuilder> {
+        let builder = try!(TcpBuilder::new_v4());
+        try!(builder.reuse_address(true));
+        let ip = self.next_ip();
+        let attempts = cmp::min(cmp::max(self.ports.len(), 1), MAX_BIND_ATTEMPTS);
+        for _ in 0..attempts {
+            let local = SocketAddrV4::new(ip, self.next_port());
+            match builder.bind(local) {
+                Ok(_) => return Ok(builder),
+                Err(ref e) if e.kind() == IoErrorKind::AddrInUse && !self.ports.is_empty() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(IoError::new(IoErrorKind::AddrInUse,
+                         format!("no free source port on {}", ip).as_str()))
+    }
+
+    /// Starts a non-blocking connection to `addr` for the engine.
+    pub fn connect(&mut self, addr: &SocketAddr) -> IoResult<mio::tcp::TcpStream> {
+        if self.is_default() {
+            return mio::tcp::TcpStream::connect(addr);
+        }
+        let builder = try!(self.bind());
+        mio::tcp::TcpStream::connect_stream(try!(builder.to_tcp_stream()), addr)
+    }
+
+    /// Connects to `addr` from the next source address, waiting at most
+    /// `connect_timeout`. Reads and writes on the stream time out after
+    /// `timeout`.
+    pub fn connect_blocking(&mut self,
+                            addr: &SocketAddr,
+                            connect_timeout: Duration,
+                            timeout: Duration)
+                            -> IoResult<net::TcpStream> {
+        let builder = if self.is_default() {
+            try!(TcpBuilder::new_v4())
+        } else {
+            try!(self.bind())
+        };
+        let stream = try!(builder.to_tcp_stream());
+        // mio connects without blocking through a handle on the same socket
+        let connecting = try!(mio::tcp::TcpStream::connect_stream(try!(stream.try_clone()), addr));
+        let poll = try!(mio::Poll::new());
+        try!(poll.register(&connecting,
+                           mio::Token(0),
+                           mio::Ready::writable(),
+                           mio::PollOpt::level()));
+        let mut events = mio::Events::with_capacity(1);
+        let started = Instant::now();
+        loop {
+            let elapsed = started.elapsed();
+            if elapsed >= connect_timeout {
+                return Err(IoError::new(IoErrorKind::TimedOut,
+                                        format!("connecting to {} timed out", addr).as_str()));
+            }
+            match poll.poll(&mut events, Some(connect_timeout - elapsed)) {
+                Ok(0) => {}
+                Ok(_) => break,
+                Err(ref e) if e.kind() == IoErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(e) = try!(connecting.take_error()) {
+            return Err(e);
+        }
+        try!(connecting.peer_addr());
+        drop(connecting);
+        try!(stream.set_nonblocking(false));
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.set_write_timeout(Some(timeout)));
+        Ok(stream)
+    }
+}
+
+#[test]
+fn test_egress() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut egress = Egress::new(vec![Ipv4Addr::new(127, 0, 0, 1)], vec![47301, 47302]);
+    let timeout = Duration::new(5, 0);
+    let mut seen = Vec::new();
+    for _ in 0..2 {
+        let stream = egress.connect_blocking(&addr, timeout, timeout).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(stream.local_addr().unwrap(), peer);
+        assert_eq!(stream.read_timeout().unwrap(), Some(timeout));
+        seen.push(peer.port());
+    }
+    assert_eq!(seen, [47301, 47302]);
+
+    let mut egress = Egress::new(Vec::new(), Vec::new());
+    assert!(egress.connect_blocking(&addr, timeout, timeout).is_ok());
+}
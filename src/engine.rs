@@ -0,0 +1,956 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 12 Oct, 2016
+
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::tcp::TcpStream;
+use openssl::crypto::hash;
+use openssl::ssl::{NonblockingSslStream, SslContext, SslMethod, SSL_VERIFY_NONE};
+use openssl::ssl::error::{NonblockingSslError, SslError};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::prelude::*;
+use std::io::{Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use egress::Egress;
+use http;
+use ratelimit::{RateLimiter, Throttle};
+
+/// A connection to be made by the engine. `payload` is written once the
+/// connection is established and the response is read until the peer closes
+/// it, the timeout fires or the engine's response limit is reached. An empty
+/// payload only tests connectivity. A probe still running at `deadline` fails
+/// no matter what stage it is in. With `tls`, the exchange happens over TLS,
+/// whatever certificate the server has. With `tunnel`, that request goes
+/// first in the clear and, once the server has answered it, the exchange
+/// happens over TLS through the tunnel it opened.
+#[derive(Debug)]
+pub struct Probe {
+    pub addr: SocketAddr,
+    pub payload: Vec<u8>,
+    pub deadline: Option<Instant>,
+    pub tls: bool,
+    pub tunnel: Option<Vec<u8>>,
+}
+
+/// The response to a probe, and the SHA-256 fingerprint of the certificate
+/// presented if the exchange went over TLS.
+#[derive(Debug)]
+pub struct Reply {
+    pub elapsed: Duration,
+    pub result: IoResult<Vec<u8>>,
+    pub certificate: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Connecting,
+    Writing(usize),
+    Reading,
+    // the server has answered the tunnel request, TLS is yet to start
+    Tunneled,
+}
+
+/// The connection to a server, wrapped in TLS if the probe asks for it.
+enum Transport {
+    Plain(TcpStream),
+    // set while TLS waits for the socket to become readable
+    Tls(NonblockingSslStream<TcpStream>, bool),
+}
+
+impl Transport {
+    fn socket(&self) -> &TcpStream {
+        match *self {
+            Transport::Plain(ref stream) => stream,
+            Transport::Tls(ref stream, _) => stream.get_ref(),
+        }
+    }
+
+    fn is_tls(&self) -> bool {
+        match *self {
+            Transport::Plain(_) => false,
+            Transport::Tls(..) => true,
+        }
+    }
+
+    fn certificate(&self) -> Option<Vec<u8>> {
+        match *self {
+            Transport::Plain(_) => None,
+            Transport::Tls(ref stream, _) => {
+                stream.ssl().peer_certificate().and_then(|x| x.fingerprint(hash::Type::SHA256))
+            }
+        }
+    }
+
+    fn wants_read(&self) -> bool {
+        match *self {
+            Transport::Plain(_) => false,
+            Transport::Tls(_, wants_read) => wants_read,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match *self {
+            Transport::Plain(ref mut stream) => stream.read(buf),
+            Transport::Tls(ref mut stream, ref mut wants_read) => {
+                let result = stream.read(buf);
+                *wants_read = false;
+                result.map_err(|e| tls_error(e, wants_read))
+            }
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match *self {
+            Transport::Plain(ref mut stream) => stream.write(buf),
+            Transport::Tls(ref mut stream, ref mut wants_read) => {
+                let result = stream.write(buf);
+                *wants_read = false;
+                result.map_err(|e| tls_error(e, wants_read))
+            }
+        }
+    }
+}
+
+// TLS waiting on the socket looks like any other non-blocking read or write
+fn tls_error(e: NonblockingSslError, wants_read: &mut bool) -> IoError {
+    match e {
+        NonblockingSslError::WantRead => {
+            *wants_read = true;
+            IoError::new(IoErrorKind::WouldBlock, "tls wants to read")
+        }
+        NonblockingSslError::WantWrite => {
+            IoError::new(IoErrorKind::WouldBlock, "tls wants to write")
+        }
+        NonblockingSslError::SslError(SslError::StreamError(e)) => e,
+        NonblockingSslError::SslError(SslError::SslSessionClosed) => {
+            IoError::new(IoErrorKind::ConnectionAborted, "tls session closed")
+        }
+        NonblockingSslError::SslError(e) => IoError::new(IoErrorKind::Other, e),
+    }
+}
+
+struct Connection<T> {
+    tag: T,
+    stream: Transport,
+    payload: Vec<u8>,
+    // what to send over TLS once a tunnel is open
+    tunneled: Option<Vec<u8>>,
+    response: Vec<u8>,
+    state: State,
+    started: Instant,
+    deadline: Option<Instant>,
+    followup: bool,
+}
+
+impl<T> Connection<T> {
+    // returns the outcome once the probe has completed
+    fn on_ready(&mut self,
+                poll: &Poll,
+                token: Token,
+                max_response: usize)
+                -> Option<IoResult<()>> {
+        if self.state == State::Connecting {
+            match self.stream.socket().take_error() {
+                Ok(None) => {}
+                Ok(Some(e)) | Err(e) => return Some(Err(e)),
+            }
+            if self.payload.is_empty() {
+                return Some(Ok(()));
+            }
+            self.state = State::Writing(0);
+        }
+
+        if let State::Writing(mut written) = self.state {
+            while written < self.payload.len() {
+                match self.stream.write(&self.payload[written..]) {
+                    Ok(0) => return Some(Err(IoError::new(IoErrorKind::WriteZero, "connection closed"))),
+                    Ok(n) => written += n,
+                    Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+                        self.state = State::Writing(written);
+                        // a TLS handshake in progress may be waiting for the server
+                        if self.stream.is_tls() {
+                            let interest = if self.stream.wants_read() {
+                                Ready::readable()
+                            } else {
+                                Ready::writable()
+                            };
+                            let socket = self.stream.socket();
+                            let rearmed = poll.reregister(socket, token, interest, PollOpt::level());
+                            if let Err(e) = rearmed {
+                                return Some(Err(e));
+                            }
+                        }
+                        return None;
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            self.state = State::Reading;
+            let socket = self.stream.socket();
+            return match poll.reregister(socket, token, Ready::readable(), PollOpt::level()) {
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Some(Ok(())),
+                Ok(n) => {
+                    self.response.extend_from_slice(&buf[..n]);
+                    // the answer to a tunnel request ends with its headers, and
+                    // is the response itself unless it lets the tunnel through
+                    if self.tunneled.is_some() && find(&self.response, b"\r\n\r\n") {
+                        let opened = http::Response::parse_partial(&self.response)
+                            .map(|x| x.is_success())
+                            .unwrap_or(false);
+                        if !opened {
+                            return Some(Ok(()));
+                        }
+                        self.state = State::Tunneled;
+                        return None;
+                    }
+                    // whatever comes after the limit is not worth waiting for
+                    if self.response.len() >= max_response {
+                        self.response.truncate(max_response);
+                        return Some(Ok(()));
+                    }
+                }
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock => return None,
+                // like a blocking read, whatever arrived before the error is the response
+                Err(_) => return Some(Ok(())),
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|x| x == needle)
+}
+
+struct Reactor<T> {
+    poll: Poll,
+    events: Events,
+    connections: HashMap<usize, Connection<T>>,
+    // every probe shares the same timeouts, so their deadlines are queued in
+    // order; deadlines set on probes themselves are kept sorted instead
+    connect_deadlines: VecDeque<(Instant, usize)>,
+    io_deadlines: VecDeque<(Instant, usize)>,
+    probe_deadlines: BTreeSet<(Instant, usize)>,
+    // probes waiting for the rate limiter
+    queued: VecDeque<(T, Probe, bool)>,
+    limiter: RateLimiter,
+    egress: Egress,
+    tls: SslContext,
+    resume_at: Option<Instant>,
+    next_token: usize,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_response: usize,
+    sweeping: usize,
+    exchanging: usize,
+}
+
+impl<T> Reactor<T> {
+    fn new(connect_timeout: Duration,
+           timeout: Duration,
+           max_response: usize,
+           limiter: RateLimiter,
+           egress: Egress)
+           -> IoResult<Reactor<T>> {
+        let mut tls = try!(SslContext::new(SslMethod::Sslv23)
+            .map_err(|e| IoError::new(IoErrorKind::Other, e)));
+        // proxies are verified by what they do, not by their certificates
+        tls.set_verify(SSL_VERIFY_NONE, None);
+        Ok(Reactor {
+            poll: try!(Poll::new()),
+            events: Events::with_capacity(1024),
+            connections: HashMap::new(),
+            connect_deadlines: VecDeque::new(),
+            io_deadlines: VecDeque::new(),
+            probe_deadlines: BTreeSet::new(),
+            queued: VecDeque::new(),
+            limiter: limiter,
+            egress: egress,
+            tls: tls,
+            resume_at: None,
+            next_token: 0,
+            connect_timeout: connect_timeout,
+            timeout: timeout,
+            max_response: max_response,
+            sweeping: 0,
+            exchanging: 0,
+        })
+    }
+
+    fn is_idle(&self) -> bool {
+        self.connections.is_empty() && self.queued.is_empty()
+    }
+
+    fn release(&mut self, followup: bool) {
+        if followup {
+            self.exchanging -= 1;
+        } else {
+            self.sweeping -= 1;
+        }
+    }
+
+    // the probe takes its slot right away, even if the rate limiter makes it wait
+    fn start(&mut self, tag: T, probe: Probe, followup: bool) {
+        if followup {
+            self.exchanging += 1;
+        } else {
+            self.sweeping += 1;
+        }
+        self.queued.push_back((tag, probe, followup));
+    }
+
+    fn dispatch(&mut self, finished: &mut Vec<(T, Reply)>) {
+        let now = Instant::now();
+        self.resume_at = None;
+        for _ in 0..self.queued.len() {
+            let (tag, probe, followup) = self.queued.pop_front().unwrap();
+            if probe.deadline.map_or(false, |x| x <= now) {
+                self.release(followup);
+                let e = IoError::new(IoErrorKind::TimedOut, "probe deadline exceeded");
+                finished.push((tag,
+                               Reply {
+                                   elapsed: Duration::new(0, 0),
+                                   result: Err(e),
+                                   certificate: None,
+                               }));
+                continue;
+            }
+            match self.limiter.admit(probe.addr.ip(), now) {
+                Ok(_) => {
+                    if let Some(x) = self.connect(tag, probe, followup) {
+                        finished.push(x);
+                    }
+                }
+                Err(Throttle::Host(wait)) => {
+                    self.defer(now + wait);
+                    self.queued.push_back((tag, probe, followup));
+                }
+                Err(Throttle::Global(wait)) => {
+                    self.defer(now + wait);
+                    self.queued.push_front((tag, probe, followup));
+                    break;
+                }
+            }
+        }
+    }
+
+    fn defer(&mut self, until: Instant) {
+        self.resume_at = match self.resume_at {
+            Some(x) if x < until => Some(x),
+            _ => Some(until),
+        };
+    }
+
+    fn connect(&mut self, tag: T, probe: Probe, followup: bool) -> Option<(T, Reply)> {
+        let started = Instant::now();
+        let token = self.next_token;
+        self.next_token = self.next_token.wrapping_add(1);
+        let tls = &self.tls;
+        let result = self.egress.connect(&probe.addr).and_then(|stream| {
+            try!(self.poll.register(&stream, Token(token), Ready::writable(), PollOpt::level()));
+            if !probe.tls {
+                return Ok(Transport::Plain(stream));
+            }
+            // the handshake goes on once the connection is up
+            NonblockingSslStream::connect(tls, stream)
+                .map(|stream| Transport::Tls(stream, false))
+                .map_err(|e| IoError::new(IoErrorKind::Other, e))
+        });
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.release(followup);
+                return Some((tag,
+                             Reply {
+                                 elapsed: started.elapsed(),
+                                 result: Err(e),
+                                 certificate: None,
+                             }));
+            }
+        };
+        self.connect_deadlines.push_back((started + self.connect_timeout, token));
+        if let Some(deadline) = probe.deadline {
+            self.probe_deadlines.insert((deadline, token));
+        }
+        // the tunnel request goes first, the payload waits for the tunnel
+        let (payload, tunneled) = match probe.tunnel {
+            Some(request) => (request, Some(probe.payload)),
+            None => (probe.payload, None),
+        };
+        self.connections.insert(token,
+                                Connection {
+                                    tag: tag,
+                                    stream: stream,
+                                    payload: payload,
+                                    tunneled: tunneled,
+                                    response: Vec::new(),
+                                    state: State::Connecting,
+                                    started: started,
+                                    deadline: probe.deadline,
+                                    followup: followup,
+                                });
+        None
+    }
+
+    fn finish(&mut self, token: usize, result: IoResult<()>) -> Option<(T, Reply)> {
+        let conn = match self.connections.remove(&token) {
+            Some(conn) => conn,
+            None => return None,
+        };
+        let _ = self.poll.deregister(conn.stream.socket());
+        if let Some(deadline) = conn.deadline {
+            self.probe_deadlines.remove(&(deadline, token));
+        }
+        self.release(conn.followup);
+        let certificate = conn.stream.certificate();
+        let Connection { tag, response, started, .. } = conn;
+        Some((tag,
+              Reply {
+                  elapsed: started.elapsed(),
+                  result: result.map(|_| response),
+                  certificate: certificate,
+              }))
+    }
+
+    // starts TLS through the tunnel a server has opened, then sends the payload
+    fn start_tls(&mut self, token: usize) -> Option<(T, Reply)> {
+        let mut conn = match self.connections.remove(&token) {
+            Some(conn) => conn,
+            None => return None,
+        };
+        let result = match conn.stream {
+            Transport::Plain(stream) => {
+                NonblockingSslStream::connect(&self.tls, stream)
+                    .map(|stream| Transport::Tls(stream, false))
+                    .map_err(|e| IoError::new(IoErrorKind::Other, e))
+            }
+            Transport::Tls(..) => Err(IoError::new(IoErrorKind::Other, "tunnel within tls")),
+        };
+        conn.stream = match result {
+            Ok(stream) => stream,
+            Err(e) => {
+                // the socket is gone along with the failed handshake
+                if let Some(deadline) = conn.deadline {
+                    self.probe_deadlines.remove(&(deadline, token));
+                }
+                self.release(conn.followup);
+                return Some((conn.tag,
+                             Reply {
+                                 elapsed: conn.started.elapsed(),
+                                 result: Err(e),
+                                 certificate: None,
+                             }));
+            }
+        };
+        conn.payload = conn.tunneled.take().unwrap_or(Vec::new());
+        conn.response.clear();
+        conn.state = State::Writing(0);
+        let rearmed = self.poll
+            .reregister(conn.stream.socket(), Token(token), Ready::writable(), PollOpt::level());
+        self.connections.insert(token, conn);
+        match rearmed {
+            Ok(_) => None,
+            Err(e) => self.finish(token, Err(e)),
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let candidates = [self.connect_deadlines.front().map(|&(x, _)| x),
+                          self.io_deadlines.front().map(|&(x, _)| x),
+                          self.probe_deadlines.iter().next().map(|&(x, _)| x),
+                          self.resume_at];
+        candidates.iter().filter_map(|x| *x).min()
+    }
+
+    fn poll(&mut self, finished: &mut Vec<(T, Reply)>) -> IoResult<()> {
+        let now = Instant::now();
+        let wait = match self.next_deadline() {
+            Some(next) if next > now => next - now,
+            Some(_) => Duration::new(0, 0),
+            None => Duration::from_millis(100),
+        };
+        match self.poll.poll(&mut self.events, Some(wait)) {
+            Ok(_) => {}
+            // a signal only cuts the wait short; whoever cares about it checks
+            // for it between polls
+            Err(ref e) if e.kind() == IoErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let ready = self.events.iter().map(|e| e.token().0).collect::<Vec<usize>>();
+        for token in ready {
+            let (done, tunneled) = match self.connections.get_mut(&token) {
+                Some(conn) => {
+                    let connecting = conn.state == State::Connecting;
+                    let done = conn.on_ready(&self.poll, Token(token), self.max_response);
+                    // the i/o timeout starts once the connection is up
+                    if connecting && done.is_none() && conn.state != State::Connecting {
+                        self.io_deadlines.push_back((Instant::now() + self.timeout, token));
+                    }
+                    (done, conn.state == State::Tunneled)
+                }
+                None => continue,
+            };
+            let reply = match done {
+                Some(result) => self.finish(token, result),
+                None if tunneled => self.start_tls(token),
+                None => None,
+            };
+            if let Some(x) = reply {
+                finished.push(x);
+            }
+        }
+        self.expire(finished);
+        Ok(())
+    }
+
+    fn expire(&mut self, finished: &mut Vec<(T, Reply)>) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while self.connect_deadlines.front().map_or(false, |&(x, _)| x <= now) {
+            let (_, token) = self.connect_deadlines.pop_front().unwrap();
+            match self.connections.get(&token) {
+                Some(conn) if conn.state == State::Connecting => {
+                    expired.push((token, Err(IoError::new(IoErrorKind::TimedOut, "connect timed out"))))
+                }
+                _ => {}
+            }
+        }
+        while self.io_deadlines.front().map_or(false, |&(x, _)| x <= now) {
+            let (_, token) = self.io_deadlines.pop_front().unwrap();
+            match self.connections.get(&token) {
+                // a blocking read would have returned what it got so far
+                Some(conn) if conn.state == State::Reading => expired.push((token, Ok(()))),
+                Some(_) => {
+                    expired.push((token, Err(IoError::new(IoErrorKind::TimedOut, "probe timed out"))))
+                }
+                None => {}
+            }
+        }
+        loop {
+            let (deadline, token) = match self.probe_deadlines.iter().next() {
+                Some(&x) if x.0 <= now => x,
+                _ => break,
+            };
+            self.probe_deadlines.remove(&(deadline, token));
+            expired.push((token, Err(IoError::new(IoErrorKind::TimedOut, "probe deadline exceeded"))));
+        }
+        for (token, result) in expired {
+            if let Some(x) = self.finish(token, result) {
+                finished.push(x);
+            }
+        }
+    }
+}
+
+/// Drives many probes concurrently over non-blocking sockets from a single
+/// thread. Probes taken from the source are limited by `sweep_limit`, probes
+/// returned by the reply handler are limited by `limit`. Connections get
+/// `connect_timeout` to be established and `timeout` for the exchange after
+/// that. No more than `max_response` bytes of a response are read. New
+/// connections are opened no faster than `rate` per second in total and
+/// `per_host_rate` per second to any single host, from the source addresses
+/// of `egress`.
+pub struct Engine {
+    sweep_limit: usize,
+    limit: usize,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_response: usize,
+    rate: Option<f64>,
+    per_host_rate: Option<f64>,
+    egress: Egress,
+}
+
+impl Engine {
+    pub fn new(sweep_limit: usize,
+               limit: usize,
+               connect_timeout: Duration,
+               timeout: Duration,
+               max_response: usize,
+               rate: Option<f64>,
+               per_host_rate: Option<f64>,
+               egress: Egress)
+               -> Engine {
+        Engine {
+            sweep_limit: sweep_limit,
+            limit: limit,
+            connect_timeout: connect_timeout,
+            timeout: timeout,
+            max_response: max_response,
+            rate: rate,
+            per_host_rate: per_host_rate,
+            egress: egress,
+        }
+    }
+
+    pub fn run<T, I, F>(&self, probes: I, on_reply: F) -> IoResult<()>
+        where I: Iterator<Item = (T, Probe)>,
+              F: FnMut(T, Reply) -> Vec<(T, Probe)>
+    {
+        self.run_until(probes, on_reply, || false, Duration::new(0, 0))
+    }
+
+    /// Like `run`, but once `stopping` returns true no more probes are taken
+    /// from the source, and whatever is still in flight after `grace` is
+    /// abandoned.
+    pub fn run_until<T, I, F, S>(&self,
+                                 probes: I,
+                                 mut on_reply: F,
+                                 stopping: S,
+                                 grace: Duration)
+                                 -> IoResult<()>
+        where I: Iterator<Item = (T, Probe)>,
+              F: FnMut(T, Reply) -> Vec<(T, Probe)>,
+              S: Fn() -> bool
+    {
+        let limiter = RateLimiter::new(self.rate, self.per_host_rate);
+        let mut reactor = try!(Reactor::new(self.connect_timeout,
+                                            self.timeout,
+                                            self.max_response,
+                                            limiter,
+                                            self.egress.clone()));
+        let mut probes = probes.fuse();
+        let mut drained = false;
+        let mut followups = VecDeque::new();
+        let mut finished = Vec::new();
+        let mut abandon_at = None;
+
+        loop {
+            if abandon_at.is_none() && stopping() {
+                abandon_at = Some(Instant::now() + grace);
+                drained = true;
+            }
+            if let Some(abandon_at) = abandon_at {
+                if Instant::now() >= abandon_at {
+                    break;
+                }
+            }
+
+            // follow-ups go first so that servers being verified finish
+            // before new targets are taken from the source
+            while reactor.exchanging < self.limit {
+                match followups.pop_front() {
+                    Some((tag, probe)) => reactor.start(tag, probe, true),
+                    None => break,
+                }
+            }
+
+            // stop taking new targets while follow-ups are backing up, so
+            // memory stays bounded no matter how large the source is
+            while !drained && reactor.sweeping < self.sweep_limit &&
+                  followups.len() < self.limit {
+                match probes.next() {
+                    Some((tag, probe)) => reactor.start(tag, probe, false),
+                    None => drained = true,
+                }
+            }
+            reactor.dispatch(&mut finished);
+
+            if drained && finished.is_empty() && followups.is_empty() && reactor.is_idle() {
+                break;
+            }
+            if finished.is_empty() {
+                try!(reactor.poll(&mut finished));
+            }
+            for (tag, reply) in finished.drain(..) {
+                followups.extend(on_reply(tag, reply));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_engine_run() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap();
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 4];
+            // connectivity probes hang up without sending anything
+            if stream.read_exact(&mut buf).is_ok() {
+                stream.write_all(&buf).unwrap();
+            }
+        }
+    });
+
+    let egress = Egress::new(Vec::new(), Vec::new());
+    let probe = |addr, payload: &[u8], deadline| {
+        Probe {
+            addr: addr,
+            payload: payload.to_vec(),
+            deadline: deadline,
+            tls: false,
+            tunnel: None,
+        }
+    };
+    let probes = vec![(1, probe(open, b"", None)), (2, probe(closed, b"", None))];
+    let mut replies = HashMap::new();
+    Engine::new(2, 2, Duration::new(5, 0), Duration::new(5, 0), 4096, None, None, egress.clone())
+        .run(probes.into_iter(), |tag, reply| {
+            replies.insert(tag, reply.result.map_err(|e| e.kind()));
+            if tag == 1 {
+                vec![(3, probe(open, b"ping", None))]
+            } else {
+                Vec::new()
+            }
+        })
+        .unwrap();
+    assert_eq!(replies.remove(&1), Some(Ok(Vec::new())));
+    assert_eq!(replies.remove(&2), Some(Err(IoErrorKind::ConnectionRefused)));
+    assert_eq!(replies.remove(&3), Some(Ok(b"ping".to_vec())));
+
+    // responses are cut at the limit
+    let probes = vec![(1, probe(open, b"ping", None))];
+    let mut result = None;
+    Engine::new(1, 1, Duration::new(5, 0), Duration::new(5, 0), 2, None, None, egress.clone())
+        .run(probes.into_iter(), |_, reply| {
+            result = Some(reply.result.map_err(|e| e.kind()));
+            Vec::new()
+        })
+        .unwrap();
+    assert_eq!(result, Some(Ok(b"pi".to_vec())));
+
+    // nothing is taken from the source once stopping
+    let probes = (0..10).map(|n| (n, probe(open, b"", None)));
+    let mut replied = 0;
+    Engine::new(2, 2, Duration::new(5, 0), Duration::new(5, 0), 4096, None, None, egress.clone())
+        .run_until(probes,
+                   |_, _| {
+                       replied += 1;
+                       Vec::new()
+                   },
+                   || true,
+                   Duration::new(1, 0))
+        .unwrap();
+    assert_eq!(replied, 0);
+
+    // a probe's own deadline cuts it short even while the timeout has not fired
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let deadline = Instant::now() + Duration::from_millis(200);
+    let probes = vec![(1, probe(silent.local_addr().unwrap(), b"ping", Some(deadline)))];
+    let mut result = None;
+    Engine::new(1, 1, Duration::new(5, 0), Duration::new(5, 0), 4096, None, None, egress.clone())
+        .run(probes.into_iter(), |_, reply| {
+            result = Some(reply.result.map_err(|e| e.kind()));
+            Vec::new()
+        })
+        .unwrap();
+    assert_eq!(result, Some(Err(IoErrorKind::TimedOut)));
+    assert!(Instant::now() < deadline + Duration::new(1, 0));
+}
+
+#[test]
+fn test_engine_interrupted() {
+    use libc;
+    use signal;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let _turn = signal::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // a server that takes its time to answer
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 4];
+            if stream.read_exact(&mut buf).is_ok() {
+                thread::sleep(Duration::from_millis(500));
+                stream.write_all(&buf).unwrap();
+            }
+        }
+    });
+
+    signal::catch_interrupt();
+    let before = signal::interrupts();
+    // the signal has to land on the thread waiting for events
+    let engine_thread = unsafe { libc::pthread_self() };
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        unsafe { libc::pthread_kill(engine_thread, libc::SIGINT) };
+    });
+    let probes = vec![((), Probe {
+                          addr: addr,
+                          payload: b"ping".to_vec(),
+                          deadline: None,
+                          tls: false,
+                          tunnel: None,
+                      })];
+    let mut result = None;
+    Engine::new(1,
+                1,
+                Duration::new(5, 0),
+                Duration::new(5, 0),
+                4096,
+                None,
+                None,
+                Egress::new(Vec::new(), Vec::new()))
+        .run_until(probes.into_iter(),
+                   |_, reply| {
+                       result = Some(reply.result.map_err(|e| e.kind()));
+                       Vec::new()
+                   },
+                   || signal::interrupts() > before,
+                   Duration::new(5, 0))
+        .unwrap();
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+    assert!(signal::interrupts() > before);
+    // the probe in flight is waited for
+    assert_eq!(result, Some(Ok(b"ping".to_vec())));
+}
+
+// a server context with a certificate of its own making, and the sha256
+// fingerprint of the certificate
+#[cfg(test)]
+pub fn self_signed_context() -> (SslContext, Option<Vec<u8>>) {
+    use openssl::x509::X509Generator;
+
+    let (cert, key) = X509Generator::new()
+        .set_bitlength(2048)
+        .set_valid_period(1)
+        .add_name("CN".to_string(), "localhost".to_string())
+        .set_sign_hash(hash::Type::SHA256)
+        .generate()
+        .unwrap();
+    let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+    ctx.set_certificate(&cert).unwrap();
+    ctx.set_private_key(&key).unwrap();
+    (ctx, cert.fingerprint(hash::Type::SHA256))
+}
+
+#[test]
+fn test_engine_tls() {
+    use openssl::ssl::SslStream;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let (ctx, _) = self_signed_context();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(mut stream) = SslStream::accept(&ctx, stream.unwrap()) {
+                let mut buf = [0u8; 4];
+                if stream.read_exact(&mut buf).is_ok() {
+                    stream.write_all(&buf).unwrap();
+                }
+            }
+        }
+    });
+
+    let probes = vec![(true, Probe {
+                          addr: addr,
+                          payload: b"ping".to_vec(),
+                          deadline: None,
+                          tls: true,
+                          tunnel: None,
+                      }),
+                      (false, Probe {
+                          addr: addr,
+                          payload: b"ping".to_vec(),
+                          deadline: None,
+                          tls: false,
+                          tunnel: None,
+                      })];
+    let mut replies = HashMap::new();
+    Engine::new(2,
+                2,
+                Duration::new(5, 0),
+                Duration::new(2, 0),
+                4096,
+                None,
+                None,
+                Egress::new(Vec::new(), Vec::new()))
+        .run(probes.into_iter(), |tls, reply| {
+            replies.insert(tls, reply.result.map_err(|e| e.kind()));
+            Vec::new()
+        })
+        .unwrap();
+    assert_eq!(replies.remove(&true), Some(Ok(b"ping".to_vec())));
+    // plain text is no handshake
+    assert!(replies.remove(&false) != Some(Ok(b"ping".to_vec())));
+}
+
+#[test]
+fn test_engine_tunnel() {
+    use openssl::ssl::SslStream;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let (ctx, fingerprint) = self_signed_context();
+    // a proxy that answers CONNECT to port 443 and then speaks TLS itself
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n") && stream.read_exact(&mut buf).is_ok() {
+                request.push(buf[0]);
+            }
+            if !request.starts_with(b"CONNECT localhost:443 ") {
+                let _ = stream.write_all(b"HTTP/1.0 403 Forbidden\r\n\r\n");
+                continue;
+            }
+            stream.write_all(b"HTTP/1.0 200 Connection established\r\n\r\n").unwrap();
+            if let Ok(mut stream) = SslStream::accept(&ctx, stream) {
+                let mut buf = [0u8; 4];
+                if stream.read_exact(&mut buf).is_ok() {
+                    stream.write_all(&buf).unwrap();
+                }
+            }
+        }
+    });
+
+    let probes = vec![(true, Probe {
+                          addr: addr,
+                          payload: b"ping".to_vec(),
+                          deadline: None,
+                          tls: false,
+                          tunnel: Some(b"CONNECT localhost:443 HTTP/1.0\r\n\r\n".to_vec()),
+                      }),
+                      (false, Probe {
+                          addr: addr,
+                          payload: b"ping".to_vec(),
+                          deadline: None,
+                          tls: false,
+                          tunnel: Some(b"CONNECT localhost:25 HTTP/1.0\r\n\r\n".to_vec()),
+                      })];
+    let mut replies = HashMap::new();
+    Engine::new(2,
+                2,
+                Duration::new(5, 0),
+                Duration::new(2, 0),
+                4096,
+                None,
+                None,
+                Egress::new(Vec::new(), Vec::new()))
+        .run(probes.into_iter(), |opened, reply| {
+            replies.insert(opened, reply);
+            Vec::new()
+        })
+        .unwrap();
+    let reply = replies.remove(&true).unwrap();
+    assert_eq!(reply.result.unwrap(), b"ping");
+    assert!(fingerprint.is_some());
+    assert_eq!(reply.certificate, fingerprint);
+    // a refused tunnel is answered in plain text
+    let reply = replies.remove(&false).unwrap();
+    assert_eq!(reply.result.unwrap(), b"HTTP/1.0 403 Forbidden\r\n\r\n");
+    assert_eq!(reply.certificate, None);
+}
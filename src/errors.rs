@@ -3,9 +3,9 @@
 error_chain!{
 
     errors {
-        InvalidIpv4Address(t: String) {
-            description("invalid ipv4 address")
-            display("invalid ipv4 address: {}", t)
+        InvalidHost(t: String) {
+            description("invalid ipv4 address or hostname")
+            display("invalid ipv4 address or hostname: {}", t)
         }
         InvalidDatabaseConnectionString(t: String) {
             description("Database connection string is invalid")
@@ -0,0 +1,136 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use regex::Regex;
+
+use http::Response;
+
+lazy_static! {
+    static ref RE_DOC_DELIMITER: Regex = Regex::new("--+\n").unwrap();
+    static ref FINGERPRINTS: Vec<Fingerprint> = parse(include_str!("fingerprint.inc"));
+}
+
+// headers proxies add to what they pass on; anything else in a response the
+// proxy has not made itself could be the origin's
+const ADDED_HEADERS: [&'static str; 3] = ["Via", "X-Cache", "Proxy-Agent"];
+
+/// Signs of a proxy software. Each signature looks at a header, the status
+/// line (`status`) or the body (`body`) of a response; the first group of a
+/// matching pattern, if any, is the version.
+#[derive(Debug)]
+pub struct Fingerprint {
+    pub name: String,
+    signatures: Vec<(String, Regex)>,
+}
+
+impl Fingerprint {
+    // the number of signatures found, and the version if told
+    fn score(&self, made: &[Response], passed: &[Response]) -> (usize, Option<String>) {
+        let mut found = 0;
+        let mut version = None;
+        for &(ref field, ref pattern) in &self.signatures {
+            let added = ADDED_HEADERS.iter().any(|x| x.to_lowercase() == field.to_lowercase());
+            let passed = if added { passed } else { &[] };
+            for resp in made.iter().chain(passed.iter()) {
+                let matched = match field.as_str() {
+                    "status" => {
+                        let status = format!("{} {} {}", resp.version, resp.status, resp.reason);
+                        pattern.captures(&status).map(|x| x.at(1).map(String::from))
+                    }
+                    "body" => {
+                        let body = String::from_utf8_lossy(&resp.body);
+                        pattern.captures(&body).map(|x| x.at(1).map(String::from))
+                    }
+                    _ => {
+                        resp.header(field)
+                            .and_then(|value| pattern.captures(value))
+                            .map(|x| x.at(1).map(String::from))
+                    }
+                };
+                if let Some(told) = matched {
+                    found += 1;
+                    version = version.or(told);
+                    break;
+                }
+            }
+        }
+        (found, version)
+    }
+}
+
+fn parse(text: &str) -> Vec<Fingerprint> {
+    RE_DOC_DELIMITER.split(text)
+        .filter_map(|doc| {
+            let mut parts = doc.splitn(2, "\n\n");
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(signatures)) => {
+                    let signatures = signatures.lines()
+                        .filter_map(|line| {
+                            let mut parts = line.splitn(2, ": ");
+                            match (parts.next(), parts.next().map(Regex::new)) {
+                                (Some(field), Some(Ok(pattern))) => {
+                                    Some((field.to_string(), pattern))
+                                }
+                                _ => None,
+                            }
+                        })
+                        .collect();
+                    Some(Fingerprint {
+                        name: name.trim().to_string(),
+                        signatures: signatures,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The proxy software that `made` responses of its own, such as error pages,
+/// and `passed` on others, and its version if it tells. Only headers proxies
+/// add are looked for in responses passed on. When several match, the one
+/// with the most signatures found wins.
+pub fn identify(made: &[Response], passed: &[Response]) -> Option<(String, Option<String>)> {
+    let mut best: Option<(usize, &Fingerprint, Option<String>)> = None;
+    for fingerprint in FINGERPRINTS.iter() {
+        let (found, version) = fingerprint.score(made, passed);
+        if found > 0 && best.as_ref().map_or(true, |&(x, _, _)| found > x) {
+            best = Some((found, fingerprint, version));
+        }
+    }
+    best.map(|(_, fingerprint, version)| (fingerprint.name.clone(), version))
+}
+
+#[test]
+fn test_identify() {
+    assert!(FINGERPRINTS.iter().all(|x| !x.signatures.is_empty()));
+
+    let squid = Response::parse(b"HTTP/1.1 503 Service Unavailable\r\nServer: squid/3.5.27\r\n\
+                                  X-Squid-Error: ERR_DNS_FAIL 0\r\n\r\n<p>Generated Wed, 19 Oct \
+                                  2016 by proxy.example.net (squid/3.5.27)</p>")
+        .unwrap();
+    assert_eq!(identify(&[squid], &[]),
+               Some(("Squid".to_string(), Some("3.5.27".to_string()))));
+
+    // proxied responses tell through the headers added on the way
+    let proxied = Response::parse(b"HTTP/1.0 200 OK\r\nServer: nginx\r\n\
+                                    Via: 1.1 proxy.example.net (tinyproxy/1.8.3)\r\n\r\n{}")
+        .unwrap();
+    let error = Response::parse(b"HTTP/1.0 500 Unable to connect\r\n\r\n").unwrap();
+    assert_eq!(identify(&[error], &[proxied]),
+               Some(("tinyproxy".to_string(), Some("1.8.3".to_string()))));
+
+    // but not through what the origin sends
+    let origin = Response::parse(b"HTTP/1.1 200 OK\r\nServer: squid/3.5.27\r\n\r\n\
+                                   <p>Download CCProxy</p>")
+        .unwrap();
+    assert_eq!(identify(&[], &[origin]), None);
+
+    let polipo = Response::parse(b"HTTP/1.1 504 Host mole.invalid lookup failed\r\n\r\n\
+                                   <p>Generated Wed, 19 Oct 2016 by Polipo on \
+                                   <em>proxy:8123</em>.")
+        .unwrap();
+    assert_eq!(identify(&[polipo], &[]), Some(("Polipo".to_string(), None)));
+
+    let unknown = Response::parse(b"HTTP/1.0 200 OK\r\nServer: nginx\r\n\r\n{}").unwrap();
+    assert_eq!(identify(&[unknown], &[]), None);
+}
@@ -1,6 +1,7 @@
 // Jianing Yang <jianingy.yang@gmail.com> @ 22 Sep, 2016
 
 use regex::{Regex, Captures};
+use std::cmp;
 use std::str::FromStr;
 use std::net::Ipv4Addr;
 
@@ -94,6 +95,22 @@ impl Ipv4Network {
         }
     }
 
+    /// Returns the first and the last address of the network.
+    pub fn bounds(&self) -> (u32, u32) {
+        (self.network, self.network + !self.netmask)
+    }
+
+    /// Like `bounds` but leaves out the network and broadcast addresses of
+    /// networks that have them, i.e. anything larger than a /31.
+    pub fn host_bounds(&self) -> (u32, u32) {
+        let (first, last) = self.bounds();
+        if last - first > 1 {
+            (first + 1, last - 1)
+        } else {
+            (first, last)
+        }
+    }
+
     fn parse_address(found: &Captures) -> Result<Ipv4Network> {
         let s_ip = try!(found.at(1)
             .ok_or(ErrorKind::UnsupportedFormat("no ip address found".to_string())));
@@ -142,6 +159,81 @@ impl Ipv4Network {
     }
 }
 
+/// A set of addresses kept as sorted, non-overlapping ranges.
+#[derive(Debug, PartialEq)]
+pub struct Ipv4RangeSet {
+    ranges: Vec<(u32, u32)>,
+}
+
+impl Ipv4RangeSet {
+    pub fn new() -> Ipv4RangeSet {
+        Ipv4RangeSet { ranges: Vec::new() }
+    }
+
+    pub fn insert(&mut self, first: u32, last: u32) {
+        let (mut first, mut last) = (first, last);
+        // ranges overlapping or adjacent to the new one are merged into it
+        let start = match self.ranges
+            .binary_search_by(|&(_, x)| (x as u64 + 1).cmp(&(first as u64))) {
+            Ok(n) | Err(n) => n,
+        };
+        let mut end = start;
+        while end < self.ranges.len() && self.ranges[end].0 as u64 <= last as u64 + 1 {
+            first = cmp::min(first, self.ranges[end].0);
+            last = cmp::max(last, self.ranges[end].1);
+            end += 1;
+        }
+        self.ranges.drain(start..end);
+        self.ranges.insert(start, (first, last));
+    }
+
+    pub fn insert_network(&mut self, network: &Ipv4Network) {
+        let (first, last) = network.bounds();
+        self.insert(first, last);
+    }
+
+    pub fn remove(&mut self, first: u32, last: u32) {
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+        for &(x, y) in &self.ranges {
+            if y < first || x > last {
+                ranges.push((x, y));
+                continue;
+            }
+            if x < first {
+                ranges.push((x, first - 1));
+            }
+            if y > last {
+                ranges.push((last + 1, y));
+            }
+        }
+        self.ranges = ranges;
+    }
+
+    pub fn remove_all(&mut self, other: &Ipv4RangeSet) {
+        for &(first, last) in &other.ranges {
+            self.remove(first, last);
+        }
+    }
+
+    pub fn ranges(&self) -> &[(u32, u32)] {
+        &self.ranges
+    }
+
+    /// Returns the number of addresses in the set.
+    pub fn len(&self) -> u64 {
+        self.ranges.iter().fold(0, |s, &(x, y)| s + (y - x) as u64 + 1)
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let ip = u32::from(ip);
+        match self.ranges.binary_search_by(|&(x, _)| x.cmp(&ip)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(n) => self.ranges[n - 1].1 >= ip,
+        }
+    }
+}
+
 #[test]
 fn test_create_network_by_cidr() {
     assert_eq!(Ipv4Network::from_str("192.168.8.5/24").unwrap(),
@@ -219,3 +311,42 @@ fn test_network_iter() {
         assert_eq!(ip, Ipv4Addr::from_str(t).unwrap());
     }
 }
+
+#[test]
+fn test_network_bounds() {
+    let network = Ipv4Network::from_str("192.168.1.0/29").unwrap();
+    assert_eq!(network.bounds(), (3232235776, 3232235783));
+    assert_eq!(network.host_bounds(), (3232235777, 3232235782));
+    let network = Ipv4Network::from_str("192.168.1.0/31").unwrap();
+    assert_eq!(network.host_bounds(), (3232235776, 3232235777));
+    let network = Ipv4Network::from_str("192.168.1.1").unwrap();
+    assert_eq!(network.host_bounds(), (3232235777, 3232235777));
+}
+
+#[test]
+fn test_range_set() {
+    let mut set = Ipv4RangeSet::new();
+    set.insert(10, 20);
+    set.insert(30, 40);
+    set.insert(50, 60);
+    assert_eq!(set.ranges, [(10, 20), (30, 40), (50, 60)]);
+    set.insert(21, 29);
+    assert_eq!(set.ranges, [(10, 40), (50, 60)]);
+    set.insert(5, 55);
+    assert_eq!(set.ranges, [(5, 60)]);
+    set.insert(0, 0);
+    set.insert(!0, !0);
+    assert_eq!(set.ranges, [(0, 0), (5, 60), (!0, !0)]);
+
+    set.remove(6, 9);
+    set.remove(58, 100);
+    set.remove(0, 0);
+    assert_eq!(set.ranges, [(5, 5), (10, 57), (!0, !0)]);
+    assert_eq!(set.len(), 50);
+
+    let mut set = Ipv4RangeSet::new();
+    set.insert_network(&Ipv4Network::from_str("10.0.0.0/8").unwrap());
+    assert!(set.contains(Ipv4Addr::new(10, 1, 2, 3)));
+    assert!(!set.contains(Ipv4Addr::new(11, 0, 0, 0)));
+    assert!(!set.contains(Ipv4Addr::new(9, 255, 255, 255)));
+}
@@ -1,8 +1,6 @@
 // Jianing Yang <jianingy.yang@gmail.com> @ 22 Sep, 2016
 #![recursion_limit = "1024"]
 
-#[macro_use]
-extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -13,10 +11,13 @@ extern crate nickel;
 extern crate error_chain;
 extern crate ansi_term;
 extern crate clap;
+extern crate encoding;
 extern crate chrono;
 extern crate env_logger;
-extern crate hyper;
+extern crate libc;
+extern crate mio;
 extern crate net2;
+extern crate openssl;
 extern crate postgres;
 extern crate r2d2;
 extern crate r2d2_postgres;
@@ -30,11 +31,26 @@ use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use env_logger::LogBuilder;
 use log::{LogRecord, LogLevel, LogLevelFilter};
 
+mod anonymity;
 mod api;
+mod checkpoint;
 mod db_api;
 mod detection;
+mod egress;
+mod engine;
+mod fingerprint;
+mod http;
 mod iprange;
+mod output;
+mod ports;
+mod progress;
+mod ratelimit;
+mod resolver;
 mod scan;
+mod signal;
+mod socks;
+mod tamper;
+mod targets;
 mod errors;
 
 static VERSION: &'static str = "0.2.1";
@@ -51,22 +67,83 @@ lazy_static! {
                  .multiple(true))
             .subcommand(SubCommand::with_name("scan")
                         .about("find proxy servers in specified network")
+                        .arg(Arg::with_name("connect-timeout")
+                             .long("connect-timeout")
+                             .takes_value(true)
+                             .default_value("5")
+                             .help("# of seconds before given up a connection"))
                         .arg(Arg::with_name("timeout")
                              .long("timeout")
                              .takes_value(true)
                              .default_value("15")
+                             .help("# of seconds to wait for a response once connected"))
+                        .arg(Arg::with_name("deadline")
+                             .long("deadline")
+                             .takes_value(true)
+                             .default_value("60")
                              .help("# of seconds before given up a verification"))
+                        .arg(Arg::with_name("max-response")
+                             .long("max-response")
+                             .takes_value(true)
+                             .default_value("65536")
+                             .help("# of bytes to read from a response at most"))
                         .arg(Arg::with_name("workers")
                              .long("workers")
                              .takes_value(true)
                              .default_value("4")
-                             .help("# of concurrent workers"))
+                             .help("max # of concurrent verification connections"))
+                        .arg(Arg::with_name("sweep-workers")
+                             .long("sweep-workers")
+                             .takes_value(true)
+                             .default_value("64")
+                             .help("max # of concurrent connections for probing open ports"))
+                        .arg(Arg::with_name("seed")
+                             .long("seed")
+                             .takes_value(true)
+                             .help("seed for the order targets are scanned in"))
                         .arg(Arg::with_name("ports")
                              .long("ports")
                              .takes_value(true)
                              .default_value("3128,8080,8123,8888")
-                             .value_delimiter(",")
-                             .help("ports to scan"))
+                             .help("ports to scan, e.g. 8000-8100,socks,!1081 \
+                                    (presets: common-http-proxy, socks)"))
+                        .arg(Arg::with_name("rate")
+                             .long("rate")
+                             .takes_value(true)
+                             .help("max # of new connections per second"))
+                        .arg(Arg::with_name("per-host-rate")
+                             .long("per-host-rate")
+                             .takes_value(true)
+                             .help("max # of new connections per second to a single host"))
+                        .arg(Arg::with_name("exclude")
+                             .long("exclude")
+                             .takes_value(true)
+                             .multiple(true)
+                             .number_of_values(1)
+                             .help("network not to be scanned"))
+                        .arg(Arg::with_name("source-ip")
+                             .long("source-ip")
+                             .takes_value(true)
+                             .multiple(true)
+                             .number_of_values(1)
+                             .help("local address to send probes from, taken in turns \
+                                    if given more than once"))
+                        .arg(Arg::with_name("source-ports")
+                             .long("source-ports")
+                             .takes_value(true)
+                             .help("local ports to send probes from, e.g. 40000-50000"))
+                        .arg(Arg::with_name("resolver")
+                             .long("resolver")
+                             .takes_value(true)
+                             .help("dns server (ip or ip:port) for resolving hostnames \
+                                    instead of the system resolver"))
+                        .arg(Arg::with_name("exclude-file")
+                             .long("exclude-file")
+                             .takes_value(true)
+                             .help("file of networks not to be scanned, one per line"))
+                        .arg(Arg::with_name("include-reserved")
+                             .long("include-reserved")
+                             .help("also scan reserved networks, network and broadcast addresses"))
                         .arg(Arg::with_name("httpbin")
                              .long("httpbin")
                              .takes_value(true)
@@ -78,27 +155,145 @@ lazy_static! {
                              .takes_value(true)
                              .default_value("www.baidu.com")
                              .help("reference for calculating latency"))
+                        .arg(Arg::with_name("credentials-file")
+                             .long("credentials-file")
+                             .takes_value(true)
+                             .help("file of user:password lines to try on proxies asking for \
+                                    Basic auth"))
+                        .arg(Arg::with_name("tamper-url")
+                             .long("tamper-url")
+                             .takes_value(true)
+                             .help("plain HTTP content proxies must pass on unchanged"))
+                        .arg(Arg::with_name("tamper-sha256")
+                             .long("tamper-sha256")
+                             .takes_value(true)
+                             .help("expected sha256 of --tamper-url, fetched directly if \
+                                    neither this nor --tamper-length is given"))
+                        .arg(Arg::with_name("tamper-length")
+                             .long("tamper-length")
+                             .takes_value(true)
+                             .help("expected length of --tamper-url"))
+                        .arg(Arg::with_name("tls-host")
+                             .long("tls-host")
+                             .takes_value(true)
+                             .help("host:port of a TLS server to reach through CONNECT \
+                                    tunnels to detect tls interception"))
+                        .arg(Arg::with_name("tls-sha256")
+                             .long("tls-sha256")
+                             .takes_value(true)
+                             .help("pinned sha256 fingerprint of the certificate of --tls-host, \
+                                    fetched directly if not given"))
+                        .arg(Arg::with_name("connect-host")
+                             .long("connect-host")
+                             .takes_value(true)
+                             .help("host of your own that proxies are asked to CONNECT to on \
+                                    each of --connect-ports"))
+                        .arg(Arg::with_name("connect-ports")
+                             .long("connect-ports")
+                             .takes_value(true)
+                             .default_value("22,80,443,8443,5222")
+                             .help("ports on --connect-host to test CONNECT with"))
                         .arg(Arg::with_name("database")
                              .long("database")
                              .takes_value(true)
-                             .required(true)
                              .help("path to database file"))
+                        .arg(Arg::with_name("output")
+                             .long("output")
+                             .takes_value(true)
+                             .help("file verified servers are written to as they are \
+                                    found; - writes to stdout"))
+                        .arg(Arg::with_name("format")
+                             .long("format")
+                             .takes_value(true)
+                             .possible_values(&["ndjson", "host:port"])
+                             .default_value("ndjson")
+                             .help("format of --output"))
+                        .arg(Arg::with_name("output-credentials")
+                             .long("output-credentials")
+                             .help("also write the credentials servers were verified with \
+                                    to --output"))
+                        .arg(Arg::with_name("checkpoint")
+                             .long("checkpoint")
+                             .takes_value(true)
+                             .default_value(".mole.checkpoint")
+                             .help("file the scan position is saved to"))
+                        .arg(Arg::with_name("resume")
+                             .long("resume")
+                             .help("continue the scan saved in the checkpoint file"))
+                        .arg(Arg::with_name("targets-file")
+                             .long("targets-file")
+                             .takes_value(true)
+                             .help("file of networks, address ranges, hosts or \
+                                    host:port pairs to scan, one per line; - reads stdin"))
                         .arg(Arg::with_name("network")
-                             .required(true)
+                             .required_unless_one(&["resume", "targets-file"])
+                             .multiple(true)
                              .takes_value(true)
-                             .help("network to scan")))
+                             .help("networks, address ranges, hosts or host:port pairs to scan")))
             .subcommand(SubCommand::with_name("verify")
                         .about("verify servers in the database")
+                        .arg(Arg::with_name("connect-timeout")
+                             .long("connect-timeout")
+                             .takes_value(true)
+                             .default_value("5")
+                             .help("# of seconds before given up a connection"))
                         .arg(Arg::with_name("timeout")
                              .long("timeout")
                              .takes_value(true)
                              .default_value("15")
+                             .help("# of seconds to wait for a response once connected"))
+                        .arg(Arg::with_name("deadline")
+                             .long("deadline")
+                             .takes_value(true)
+                             .default_value("60")
                              .help("# of seconds before given up a verification"))
+                        .arg(Arg::with_name("max-response")
+                             .long("max-response")
+                             .takes_value(true)
+                             .default_value("65536")
+                             .help("# of bytes to read from a response at most"))
                         .arg(Arg::with_name("workers")
                              .long("workers")
                              .takes_value(true)
                              .default_value("4")
-                             .help("# of concurrent workers"))
+                             .help("max # of concurrent verification connections"))
+                        .arg(Arg::with_name("rate")
+                             .long("rate")
+                             .takes_value(true)
+                             .help("max # of new connections per second"))
+                        .arg(Arg::with_name("per-host-rate")
+                             .long("per-host-rate")
+                             .takes_value(true)
+                             .help("max # of new connections per second to a single host"))
+                        .arg(Arg::with_name("exclude")
+                             .long("exclude")
+                             .takes_value(true)
+                             .multiple(true)
+                             .number_of_values(1)
+                             .help("network not to be scanned"))
+                        .arg(Arg::with_name("source-ip")
+                             .long("source-ip")
+                             .takes_value(true)
+                             .multiple(true)
+                             .number_of_values(1)
+                             .help("local address to send probes from, taken in turns \
+                                    if given more than once"))
+                        .arg(Arg::with_name("source-ports")
+                             .long("source-ports")
+                             .takes_value(true)
+                             .help("local ports to send probes from, e.g. 40000-50000"))
+                        .arg(Arg::with_name("resolver")
+                             .long("resolver")
+                             .takes_value(true)
+                             .help("dns server (ip or ip:port) for resolving hostnames \
+                                    instead of the system resolver"))
+                        .arg(Arg::with_name("exclude-file")
+                             .long("exclude-file")
+                             .takes_value(true)
+                             .help("file of networks not to be scanned, one per line"))
+                        .arg(Arg::with_name("include-reserved")
+                             .long("include-reserved")
+                             .help("also scan reserved networks, network and broadcast addresses"))
                         .arg(Arg::with_name("httpbin")
                              .long("httpbin")
                              .takes_value(true)
@@ -109,6 +304,44 @@ lazy_static! {
                              .takes_value(true)
                              .default_value("http://www.baidu.com/")
                              .help("reference for calculating latency"))
+                        .arg(Arg::with_name("credentials-file")
+                             .long("credentials-file")
+                             .takes_value(true)
+                             .help("file of user:password lines to try on proxies asking for \
+                                    Basic auth"))
+                        .arg(Arg::with_name("tamper-url")
+                             .long("tamper-url")
+                             .takes_value(true)
+                             .help("plain HTTP content proxies must pass on unchanged"))
+                        .arg(Arg::with_name("tamper-sha256")
+                             .long("tamper-sha256")
+                             .takes_value(true)
+                             .help("expected sha256 of --tamper-url, fetched directly if \
+                                    neither this nor --tamper-length is given"))
+                        .arg(Arg::with_name("tamper-length")
+                             .long("tamper-length")
+                             .takes_value(true)
+                             .help("expected length of --tamper-url"))
+                        .arg(Arg::with_name("tls-host")
+                             .long("tls-host")
+                             .takes_value(true)
+                             .help("host:port of a TLS server to reach through CONNECT \
+                                    tunnels to detect tls interception"))
+                        .arg(Arg::with_name("tls-sha256")
+                             .long("tls-sha256")
+                             .takes_value(true)
+                             .help("pinned sha256 fingerprint of the certificate of --tls-host, \
+                                    fetched directly if not given"))
+                        .arg(Arg::with_name("connect-host")
+                             .long("connect-host")
+                             .takes_value(true)
+                             .help("host of your own that proxies are asked to CONNECT to on \
+                                    each of --connect-ports"))
+                        .arg(Arg::with_name("connect-ports")
+                             .long("connect-ports")
+                             .takes_value(true)
+                             .default_value("22,80,443,8443,5222")
+                             .help("ports on --connect-host to test CONNECT with"))
                         .arg(Arg::with_name("database")
                              .long("database")
                              .takes_value(true)
@@ -127,6 +360,25 @@ lazy_static! {
                              .required(true)
                              .takes_value(true)
                              .help("file to import")))
+            .subcommand(SubCommand::with_name("exclude")
+                        .about("manage the do-not-scan list")
+                        .arg(Arg::with_name("database")
+                             .long("database")
+                             .takes_value(true)
+                             .required(true)
+                             .default_value(".mole.sqlite")
+                             .help("path to database file"))
+                        .arg(Arg::with_name("delete")
+                             .long("delete")
+                             .help("remove networks from the list"))
+                        .arg(Arg::with_name("list")
+                             .long("list")
+                             .help("show networks on the list"))
+                        .arg(Arg::with_name("network")
+                             .required_unless("list")
+                             .multiple(true)
+                             .takes_value(true)
+                             .help("networks never to be scanned")))
             .subcommand(SubCommand::with_name("serve")
                         .about("start api server")
                         .arg(Arg::with_name("bind")
@@ -185,6 +437,8 @@ fn main() {
         scan::run_verify(subopts.clone());
     } else if let Some(subopts) = OPTIONS.subcommand_matches("import") {
         scan::run_import(subopts.clone()).unwrap();
+    } else if let Some(subopts) = OPTIONS.subcommand_matches("exclude") {
+        scan::run_exclude(subopts.clone());
     } else if let Some(subopts) = OPTIONS.subcommand_matches("serve") {
         api::run_api(subopts.clone());
     }
@@ -0,0 +1,123 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use serde_json::value::{ToJson, Value};
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::io::{Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
+
+use db_api::ProxyServer;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // one json object per line
+    Json,
+    // host:port per line
+    HostPort,
+}
+
+impl Format {
+    pub fn from_str(s: &str) -> IoResult<Format> {
+        match s {
+            "ndjson" => Ok(Format::Json),
+            "host:port" => Ok(Format::HostPort),
+            _ => Err(IoError::new(IoErrorKind::InvalidInput,
+                                  format!("unknown output format: {}", s).as_str())),
+        }
+    }
+}
+
+/// Where verified servers are written to as soon as they are found.
+pub struct Output {
+    writer: Box<Write + Send>,
+    format: Format,
+    credentials: bool,
+}
+
+impl Output {
+    /// Opens `path` for writing, or stdout if `path` is "-". Existing files
+    /// are appended to if `append` is set and truncated otherwise.
+    pub fn open(path: &str, format: Format, append: bool) -> IoResult<Output> {
+        let writer: Box<Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(try!(OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)))
+        };
+        Ok(Output::new(writer, format))
+    }
+
+    pub fn new(writer: Box<Write + Send>, format: Format) -> Output {
+        Output {
+            writer: writer,
+            format: format,
+            credentials: false,
+        }
+    }
+
+    /// Also writes the credentials servers were verified with, which are
+    /// left out otherwise.
+    pub fn include_credentials(&mut self, credentials: bool) {
+        self.credentials = credentials;
+    }
+
+    pub fn write(&mut self, server: &ProxyServer) -> IoResult<()> {
+        let credentials = if self.credentials {
+            server.credentials.as_ref()
+        } else {
+            None
+        };
+        let line = match (self.format, credentials) {
+            (Format::Json, Some(credentials)) => {
+                let mut json = server.to_json();
+                if let Value::Object(ref mut map) = json {
+                    map.insert("credentials".to_string(), credentials.to_json());
+                }
+                json.to_string()
+            }
+            (Format::Json, None) => server.to_json().to_string(),
+            (Format::HostPort, Some(credentials)) => format!("{}@{}", credentials, server),
+            (Format::HostPort, None) => server.to_string(),
+        };
+        try!(writeln!(self.writer, "{}", line));
+        // whoever reads the other end sees each server as it is found
+        self.writer.flush()
+    }
+}
+
+#[test]
+fn test_output() {
+    use libc;
+    use serde_json::{self, Value};
+    use std::env;
+    use std::fs::{self, File};
+
+    // tests of other checkouts may be running at the same time
+    let path = env::temp_dir().join(format!("mole-test-output-{}", unsafe { libc::getpid() }));
+    let path = path.to_str().unwrap();
+    let mut server = ProxyServer::new("proxy.example.net", 3128, None, None, None).unwrap();
+    server.credentials = Some("user:secret".to_string());
+    Output::open(path, Format::HostPort, false).unwrap().write(&server).unwrap();
+    Output::open(path, Format::Json, true).unwrap().write(&server).unwrap();
+    let mut output = Output::open(path, Format::HostPort, true).unwrap();
+    output.include_credentials(true);
+    output.write(&server).unwrap();
+
+    let mut content = String::new();
+    File::open(path).unwrap().read_to_string(&mut content).unwrap();
+    fs::remove_file(path).unwrap();
+    let lines = content.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "proxy.example.net:3128");
+    let data: Value = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(data.find("port").and_then(|x| x.as_u64()), Some(3128));
+    // credentials are only written when asked for
+    assert_eq!(data.find("has_credentials").and_then(|x| x.as_bool()), Some(true));
+    assert!(data.find("credentials").is_none());
+    assert_eq!(lines[2], "user:secret@proxy.example.net:3128");
+    assert!(Format::from_str("xml").is_err());
+}
@@ -0,0 +1,104 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use std::collections::BTreeSet;
+
+error_chain! {
+
+    errors {
+        InvalidPort(t: String) {
+            description("invalid port")
+            display("invalid port: {}", t)
+        }
+        InvalidRange(t: String) {
+            description("invalid port range")
+            display("invalid port range: {}", t)
+        }
+        UnknownPreset(t: String) {
+            description("unknown port preset")
+            display("unknown port preset: {}", t)
+        }
+        NoPorts(t: String) {
+            description("no ports left to scan")
+            display("no ports left to scan: {}", t)
+        }
+    }
+
+}
+
+// ports commonly used by each kind of proxy server
+static PRESETS: &'static [(&'static str, &'static [u16])] =
+    &[("common-http-proxy", &[80, 81, 3128, 8000, 8080, 8081, 8088, 8118, 8123, 8888, 9999]),
+      ("socks", &[1080, 1081, 9050, 9150])];
+
+fn parse_port(s: &str) -> Result<u16> {
+    match s.parse::<u16>() {
+        Ok(0) | Err(_) => Err(ErrorKind::InvalidPort(s.to_string()).into()),
+        Ok(port) => Ok(port),
+    }
+}
+
+fn parse_item(item: &str) -> Result<Vec<u16>> {
+    if let Some(&(_, ports)) = PRESETS.iter().find(|&&(name, _)| name == item) {
+        return Ok(ports.to_vec());
+    }
+    if item.starts_with(|c: char| c.is_alphabetic()) {
+        return Err(ErrorKind::UnknownPreset(item.to_string()).into());
+    }
+    let mut bounds = item.splitn(2, '-');
+    match (bounds.next(), bounds.next()) {
+        (Some(port), None) => Ok(vec![try!(parse_port(port.trim()))]),
+        (Some(first), Some(last)) => {
+            let first = try!(parse_port(first.trim())
+                .chain_err(|| ErrorKind::InvalidRange(item.to_string())));
+            let last = try!(parse_port(last.trim())
+                .chain_err(|| ErrorKind::InvalidRange(item.to_string())));
+            if first > last {
+                return Err(ErrorKind::InvalidRange(item.to_string()).into());
+            }
+            Ok((first..last).chain(Some(last)).collect())
+        }
+        _ => Err(ErrorKind::InvalidPort(item.to_string()).into()),
+    }
+}
+
+/// Parses a comma separated list of ports, port ranges (`8000-8100`) and
+/// presets (`common-http-proxy`, `socks`). Items starting with `!` are left
+/// out, e.g. `common-http-proxy,!80`. Ports are returned in ascending order.
+pub fn parse(spec: &str) -> Result<Vec<u16>> {
+    let mut included = BTreeSet::new();
+    let mut excluded = BTreeSet::new();
+    for item in spec.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        if item.starts_with('!') {
+            excluded.extend(try!(parse_item(item[1..].trim())));
+        } else {
+            included.extend(try!(parse_item(item)));
+        }
+    }
+    let ports = included.difference(&excluded).cloned().collect::<Vec<u16>>();
+    if ports.is_empty() {
+        return Err(ErrorKind::NoPorts(spec.to_string()).into());
+    }
+    Ok(ports)
+}
+
+#[test]
+fn test_parse_ports() {
+    assert_eq!(parse("8080").unwrap(), vec![8080]);
+    assert_eq!(parse("8080, 3128,8080").unwrap(), vec![3128, 8080]);
+    assert_eq!(parse("8000-8003,!8001").unwrap(), vec![8000, 8002, 8003]);
+    assert_eq!(parse("65534-65535").unwrap(), vec![65534, 65535]);
+    assert_eq!(parse("socks,!9050-9150").unwrap(), vec![1080, 1081]);
+    assert!(parse("common-http-proxy").unwrap().contains(&3128));
+}
+
+#[test]
+fn test_parse_ports_invalid() {
+    assert!(parse("80a").is_err());
+    assert!(parse("0").is_err());
+    assert!(parse("65536").is_err());
+    assert!(parse("8100-8000").is_err());
+    assert!(parse("8000-").is_err());
+    assert!(parse("http").is_err());
+    assert!(parse("80,!80").is_err());
+    assert!(parse("").is_err());
+}
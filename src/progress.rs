@@ -0,0 +1,250 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use libc;
+use ratelimit::as_secs_f64;
+use serde_json::Value;
+use serde_json::value::ToJson;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often the status is shown on a terminal and logged otherwise
+const TTY_INTERVAL: u64 = 1;
+const LOG_INTERVAL: u64 = 15;
+// targets per second are measured over this many seconds
+const RATE_WINDOW: u64 = 10;
+
+// h:mm:ss
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Counters updated by the scanner as targets are worked through.
+#[derive(Debug)]
+pub struct Progress {
+    total: Option<u64>,
+    started: Instant,
+    done: AtomicUsize,
+    open: AtomicUsize,
+    verified: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new(total: Option<u64>) -> Progress {
+        Progress {
+            total: total,
+            started: Instant::now(),
+            done: AtomicUsize::new(0),
+            open: AtomicUsize::new(0),
+            verified: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+        }
+    }
+
+    /// A target has been dealt with, whatever the outcome.
+    pub fn add_done(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_open(&self) {
+        self.open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_verified(&self) {
+        self.verified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A probe failed for a reason other than a closed or silent port.
+    pub fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            total: self.total,
+            done: self.done.load(Ordering::Relaxed) as u64,
+            open: self.open.load(Ordering::Relaxed) as u64,
+            verified: self.verified.load(Ordering::Relaxed) as u64,
+            errors: self.errors.load(Ordering::Relaxed) as u64,
+            elapsed: self.started.elapsed(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub total: Option<u64>,
+    pub done: u64,
+    pub open: u64,
+    pub verified: u64,
+    pub errors: u64,
+    pub elapsed: Duration,
+}
+
+impl Snapshot {
+    /// Targets done per second since `earlier`.
+    pub fn rate(&self, earlier: &Snapshot) -> f64 {
+        let secs = as_secs_f64(self.elapsed) - as_secs_f64(earlier.elapsed);
+        if secs > 0f64 {
+            (self.done - earlier.done) as f64 / secs
+        } else {
+            0f64
+        }
+    }
+
+    pub fn eta(&self, rate: f64) -> Option<Duration> {
+        match self.total {
+            Some(total) if rate > 0f64 && total >= self.done => {
+                Some(Duration::new(((total - self.done) as f64 / rate).ceil() as u64, 0))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_line(&self, rate: f64) -> String {
+        let done = match self.total {
+            Some(total) if total > 0 => {
+                format!("{:.2}% ({}/{})",
+                        100f64 * self.done as f64 / total as f64,
+                        self.done,
+                        total)
+            }
+            _ => format!("{}", self.done),
+        };
+        let eta = self.eta(rate).map_or("-".to_string(), format_duration);
+        format!("progress {}, {} open, {} verified, {} errors, {:.1} targets/s, eta {}",
+                done,
+                self.open,
+                self.verified,
+                self.errors,
+                rate,
+                eta)
+    }
+
+    /// One line account of a finished run.
+    pub fn summary(&self) -> String {
+        let skipped = match self.total {
+            Some(total) if total > self.done => format!(", {} not done", total - self.done),
+            _ => String::new(),
+        };
+        format!("{} targets done in {}: {} open, {} verified, {} errors{}.",
+                self.done,
+                format_duration(self.elapsed),
+                self.open,
+                self.verified,
+                self.errors,
+                skipped)
+    }
+
+    pub fn to_json(&self, rate: f64) -> Value {
+        let mut map = BTreeMap::new();
+        map.insert("done".to_string(), self.done.to_json());
+        if let Some(total) = self.total {
+            map.insert("total".to_string(), total.to_json());
+        }
+        map.insert("open".to_string(), self.open.to_json());
+        map.insert("verified".to_string(), self.verified.to_json());
+        map.insert("errors".to_string(), self.errors.to_json());
+        map.insert("rate".to_string(), rate.to_json());
+        map.insert("elapsed".to_string(), self.elapsed.as_secs().to_json());
+        if let Some(eta) = self.eta(rate) {
+            map.insert("eta".to_string(), eta.as_secs().to_json());
+        }
+        Value::Object(map)
+    }
+}
+
+/// Shows the progress on stderr: a single line redrawn in place on a
+/// terminal, a json status line every now and then otherwise.
+pub struct Reporter {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Reporter {
+    pub fn start(progress: Arc<Progress>) -> Reporter {
+        let tty = unsafe { libc::isatty(libc::STDERR_FILENO) == 1 };
+        let interval = Duration::new(if tty { TTY_INTERVAL } else { LOG_INTERVAL }, 0);
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut recent = VecDeque::new();
+            recent.push_back(progress.snapshot());
+            let mut shown = Instant::now();
+            loop {
+                thread::sleep(Duration::from_millis(100));
+                let stopped = rx.try_recv().is_ok();
+                if !stopped && shown.elapsed() < interval {
+                    continue;
+                }
+                let snapshot = progress.snapshot();
+                while recent.len() > 1 &&
+                      snapshot.elapsed - recent[0].elapsed > Duration::new(RATE_WINDOW, 0) {
+                    recent.pop_front();
+                }
+                let rate = snapshot.rate(&recent[0]);
+                let mut stderr = io::stderr();
+                let _ = if tty {
+                    write!(stderr, "\r{}\x1b[K", snapshot.to_line(rate))
+                } else {
+                    writeln!(stderr, "{}", snapshot.to_json(rate))
+                };
+                recent.push_back(snapshot);
+                shown = Instant::now();
+                if stopped {
+                    if tty {
+                        let _ = writeln!(stderr, "");
+                    }
+                    return;
+                }
+            }
+        });
+        Reporter {
+            stop: tx,
+            thread: thread,
+        }
+    }
+
+    /// Shows the final status and stops.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let earlier = Snapshot {
+        total: Some(1000),
+        done: 100,
+        open: 0,
+        verified: 0,
+        errors: 0,
+        elapsed: Duration::new(10, 0),
+    };
+    let snapshot = Snapshot {
+        total: Some(1000),
+        done: 300,
+        open: 20,
+        verified: 2,
+        errors: 1,
+        elapsed: Duration::new(20, 0),
+    };
+    let rate = snapshot.rate(&earlier);
+    assert_eq!(rate, 20f64);
+    assert_eq!(snapshot.eta(rate), Some(Duration::new(35, 0)));
+    assert_eq!(snapshot.eta(0f64), None);
+    assert_eq!(snapshot.to_line(rate),
+               "progress 30.00% (300/1000), 20 open, 2 verified, 1 errors, 20.0 targets/s, \
+                eta 0:00:35");
+    assert_eq!(snapshot.summary(),
+               "300 targets done in 0:00:20: 20 open, 2 verified, 1 errors, 700 not done.");
+    let data = snapshot.to_json(rate);
+    assert_eq!(data.find("eta").and_then(|x| x.as_u64()), Some(35));
+    assert_eq!(data.find("verified").and_then(|x| x.as_u64()), Some(2));
+}
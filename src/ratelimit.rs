@@ -0,0 +1,168 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 14 Oct, 2016
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// `d` in fractional seconds.
+pub fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+fn from_secs_f64(secs: f64) -> Duration {
+    Duration::new(secs as u64, ((secs - secs.floor()) * 1e9) as u32)
+}
+
+/// Allows `rate` events per second with bursts of up to one second's worth.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, now: Instant) -> TokenBucket {
+        let capacity = if rate > 1f64 { rate } else { 1f64 };
+        TokenBucket {
+            rate: rate,
+            capacity: capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let elapsed = as_secs_f64(now - self.updated);
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.updated = now;
+        }
+    }
+
+    /// Returns how long to wait until a token is available.
+    pub fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1f64 {
+            Ok(())
+        } else {
+            Err(from_secs_f64((1f64 - self.tokens) / self.rate))
+        }
+    }
+
+    pub fn take(&mut self) {
+        self.tokens -= 1f64;
+    }
+
+    // a full bucket behaves exactly like a fresh one and can be dropped
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Throttle {
+    Global(Duration),
+    Host(Duration),
+}
+
+/// Limits how fast new connections are opened, in total and per target host.
+#[derive(Debug)]
+pub struct RateLimiter {
+    global: Option<TokenBucket>,
+    per_host_rate: Option<f64>,
+    hosts: HashMap<IpAddr, TokenBucket>,
+    pruned: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<f64>, per_host_rate: Option<f64>) -> RateLimiter {
+        let now = Instant::now();
+        RateLimiter {
+            global: rate.map(|x| TokenBucket::new(x, now)),
+            per_host_rate: per_host_rate,
+            hosts: HashMap::new(),
+            pruned: now,
+        }
+    }
+
+    /// Takes a token for a new connection to `host` if both the global and
+    /// the per-host budget allow it.
+    pub fn admit(&mut self, host: IpAddr, now: Instant) -> Result<(), Throttle> {
+        if let Some(ref mut global) = self.global {
+            try!(global.check(now).map_err(Throttle::Global));
+        }
+        if let Some(rate) = self.per_host_rate {
+            if now > self.pruned + Duration::new(1, 0) {
+                let mut idle = Vec::new();
+                for (host, bucket) in self.hosts.iter_mut() {
+                    if bucket.is_full(now) {
+                        idle.push(*host);
+                    }
+                }
+                for host in idle {
+                    self.hosts.remove(&host);
+                }
+                self.pruned = now;
+            }
+            let bucket = self.hosts.entry(host).or_insert_with(|| TokenBucket::new(rate, now));
+            try!(bucket.check(now).map_err(Throttle::Host));
+            bucket.take();
+        }
+        if let Some(ref mut global) = self.global {
+            global.take();
+        }
+        Ok(())
+    }
+}
+
+/// Parses a rate in events per second, which has to be a positive number:
+/// a bucket never refilling would hold everything up for good.
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0f64 => Ok(rate),
+        Ok(_) => Err(format!("rate must be a positive number: {}", s)),
+        Err(_) => Err(format!("invalid rate: {}", s)),
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(2f64, now);
+    assert_eq!(bucket.check(now), Ok(()));
+    bucket.take();
+    assert_eq!(bucket.check(now), Ok(()));
+    bucket.take();
+    assert_eq!(bucket.check(now), Err(Duration::from_millis(500)));
+    assert_eq!(bucket.check(now + Duration::from_millis(500)), Ok(()));
+}
+
+#[test]
+fn test_rate_limiter() {
+    use std::net::Ipv4Addr;
+
+    let now = Instant::now();
+    let (a, b) = (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+    let mut limiter = RateLimiter::new(Some(2f64), Some(1f64));
+    assert_eq!(limiter.admit(a, now), Ok(()));
+    assert_eq!(limiter.admit(a, now), Err(Throttle::Host(Duration::new(1, 0))));
+    assert_eq!(limiter.admit(b, now), Ok(()));
+    assert_eq!(limiter.admit(b, now), Err(Throttle::Global(Duration::from_millis(500))));
+
+    let mut limiter = RateLimiter::new(None, None);
+    for _ in 0..1000 {
+        assert_eq!(limiter.admit(a, now), Ok(()));
+    }
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(parse_rate("2.5"), Ok(2.5f64));
+    assert_eq!(parse_rate("1000"), Ok(1000f64));
+    for rate in &["0", "-1", "inf", "NaN", "fast", ""] {
+        assert!(parse_rate(rate).is_err());
+    }
+}
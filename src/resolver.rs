@@ -0,0 +1,234 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_THREADS: usize = 16;
+const ATTEMPTS: usize = 2;
+
+fn malformed() -> IoError {
+    IoError::new(IoErrorKind::InvalidData, "malformed dns response")
+}
+
+/// Tells hostnames apart from ip addresses, networks and ranges: every label
+/// is made of letters, digits and inner dashes, and the last one is not a number.
+pub fn is_hostname(s: &str) -> bool {
+    let labels = s.trim_right_matches('.').split('.').collect::<Vec<&str>>();
+    if s.is_empty() || s.len() > 253 {
+        return false;
+    }
+    let valid = labels.iter().all(|x| {
+        !x.is_empty() && x.len() <= 63 && !x.starts_with('-') && !x.ends_with('-') &&
+        x.chars().all(|c| ((c as u32) < 128 && c.is_alphanumeric()) || c == '-')
+    });
+    valid && !labels[labels.len() - 1].chars().all(|c| c.is_digit(10))
+}
+
+fn build_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = vec![(id >> 8) as u8, id as u8,
+                         0x01, 0x00, // recursion desired
+                         0x00, 0x01, // one question
+                         0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    for label in name.trim_right_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]); // root, type A, class IN
+    query
+}
+
+// returns the offset right after the (possibly compressed) name at `offset`
+fn skip_name(packet: &[u8], mut offset: usize) -> IoResult<usize> {
+    loop {
+        let len = *try!(packet.get(offset).ok_or(malformed())) as usize;
+        if len == 0 {
+            return Ok(offset + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Ok(offset + 2);
+        }
+        offset += len + 1;
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> IoResult<u16> {
+    match (packet.get(offset), packet.get(offset + 1)) {
+        (Some(&x), Some(&y)) => Ok(((x as u16) << 8) | y as u16),
+        _ => Err(malformed()),
+    }
+}
+
+// picks the first A record out of a response
+fn parse_response(packet: &[u8], name: &str) -> IoResult<Ipv4Addr> {
+    let flags = try!(read_u16(packet, 2));
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(IoError::new(IoErrorKind::NotFound, format!("{} not found", name).as_str())),
+        n => return Err(IoError::new(IoErrorKind::Other, format!("dns error {}", n).as_str())),
+    }
+    let questions = try!(read_u16(packet, 4));
+    let answers = try!(read_u16(packet, 6));
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = try!(skip_name(packet, offset)) + 4;
+    }
+    for _ in 0..answers {
+        offset = try!(skip_name(packet, offset));
+        let kind = try!(read_u16(packet, offset));
+        let len = try!(read_u16(packet, offset + 8)) as usize;
+        offset += 10;
+        if kind == 1 && len == 4 {
+            if packet.len() < offset + 4 {
+                return Err(malformed());
+            }
+            let data = &packet[offset..offset + 4];
+            return Ok(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+        }
+        offset += len;
+    }
+    Err(IoError::new(IoErrorKind::NotFound, format!("{} has no ipv4 address", name).as_str()))
+}
+
+/// Resolves hostnames to ipv4 addresses, either through the system resolver
+/// or by asking the dns server at `server` directly.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    server: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Resolver {
+    pub fn new(server: Option<SocketAddr>, timeout: Duration) -> Resolver {
+        Resolver {
+            server: server,
+            timeout: timeout,
+        }
+    }
+
+    pub fn resolve(&self, name: &str) -> IoResult<Ipv4Addr> {
+        let server = match self.server {
+            Some(server) => server,
+            None => {
+                let mut addrs = try!((name, 0).to_socket_addrs());
+                return addrs.filter_map(|x| match x {
+                        SocketAddr::V4(x) => Some(*x.ip()),
+                        _ => None,
+                    })
+                    .next()
+                    .ok_or(IoError::new(IoErrorKind::NotFound,
+                                        format!("{} has no ipv4 address", name).as_str()));
+            }
+        };
+
+        let socket = try!(UdpSocket::bind(match server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        }));
+        try!(socket.set_read_timeout(Some(self.timeout)));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        let id = (now.subsec_nanos() >> 8) as u16;
+        let query = build_query(id, name);
+        let mut buf = [0u8; 1500];
+        let mut last_error = IoError::new(IoErrorKind::TimedOut, "dns query timed out");
+        for _ in 0..ATTEMPTS {
+            try!(socket.send_to(&query, server));
+            loop {
+                match socket.recv_from(&mut buf) {
+                    // anything not answering our query is ignored
+                    Ok((n, from)) if from == server && n >= 12 &&
+                                     read_u16(&buf, 0).ok() == Some(id) => {
+                        return parse_response(&buf[..n], name);
+                    }
+                    Ok(_) => continue,
+                    Err(e) => {
+                        last_error = e;
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Resolves many names at once; names that cannot be resolved are left out.
+    pub fn resolve_all(&self, names: Vec<String>) -> HashMap<String, Ipv4Addr> {
+        let num_threads = cmp::min(names.len(), MAX_THREADS);
+        let queue = Arc::new(Mutex::new(names));
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..num_threads {
+            let (queue, tx, resolver) = (queue.clone(), tx.clone(), self.clone());
+            thread::spawn(move || loop {
+                let name = match queue.lock().unwrap().pop() {
+                    Some(name) => name,
+                    None => break,
+                };
+                let result = resolver.resolve(&name);
+                let _ = tx.send((name, result));
+            });
+        }
+        drop(tx);
+
+        let mut resolved = HashMap::new();
+        for (name, result) in rx.iter() {
+            match result {
+                Ok(ip) => {
+                    debug!("{} resolved to {}.", name, ip);
+                    resolved.insert(name, ip);
+                }
+                Err(e) => warn!("cannot resolve {}: {}", name, e),
+            }
+        }
+        resolved
+    }
+}
+
+#[test]
+fn test_is_hostname() {
+    assert!(is_hostname("proxy.example.net"));
+    assert!(is_hostname("my-proxy.example.net."));
+    assert!(is_hostname("localhost"));
+    assert!(!is_hostname("10.0.0.1"));
+    assert!(!is_hostname("10.0.0.1-10.0.0.5"));
+    assert!(!is_hostname("10.0.0.0/24"));
+    assert!(!is_hostname("-proxy.example.net"));
+    assert!(!is_hostname(""));
+}
+
+#[test]
+fn test_resolve() {
+    let stub = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = stub.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        loop {
+            let (n, from) = stub.recv_from(&mut buf).unwrap();
+            let mut response = buf[..n].to_vec();
+            response[2] |= 0x80;
+            if &buf[13..18] == b"proxy" {
+                response[7] = 2;
+                // a cname first, then the address, both pointing back at the question
+                response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0, 0, 0, 60,
+                                             0x00, 0x02, 0xc0, 0x0c]);
+                response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 60,
+                                             0x00, 0x04, 10, 1, 2, 3]);
+            } else {
+                response[3] |= 0x03;
+            }
+            stub.send_to(&response, from).unwrap();
+        }
+    });
+
+    let resolver = Resolver::new(Some(server), Duration::new(2, 0));
+    assert_eq!(resolver.resolve("proxy.example.net").unwrap(), Ipv4Addr::new(10, 1, 2, 3));
+    assert_eq!(resolver.resolve("missing.example.net").unwrap_err().kind(),
+               IoErrorKind::NotFound);
+    let resolved = resolver.resolve_all(vec!["proxy.example.net".to_string(),
+                                             "missing.example.net".to_string()]);
+    assert_eq!(resolved.len(), 1);
+    assert_eq!(resolved.get("proxy.example.net"), Some(&Ipv4Addr::new(10, 1, 2, 3)));
+}
@@ -1,73 +1,408 @@
 // Jianing Yang <jianingy.yang@gmail.com> @ 22 Sep, 2016
 
 use clap::ArgMatches;
-use hyper;
-use net2::TcpBuilder;
+use openssl::crypto::hash;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_NONE};
 use serde_json::{self, Value};
-use std::fs::File;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io;
 use std::io::prelude::*;
 use std::io::{Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::path::Path;
+use std::process;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
-use std::time::{Duration, Instant};
-use std;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use anonymity::Anonymity;
+use checkpoint;
 use db_api;
+use egress::Egress;
+use engine::{Engine, Probe, Reply};
+use http;
 use iprange;
+use progress;
+use ratelimit;
+use output;
+use ports;
+use resolver;
 use detection;
+use fingerprint;
+use signal;
+use socks;
+use tamper;
+use targets;
 
 #[derive(Debug, Clone)]
 struct ScanOptions {
     reference: String,
     httpbin: String,
     num_workers: usize,
+    // None skips the connect sweep and verifies every target right away
+    num_sweepers: Option<usize>,
+    connect_timeout: Duration,
     timeout: Duration,
-    gateway: Option<String>,
+    // how long verifying a server may take in total
+    deadline: Duration,
+    max_response: usize,
+    // new connections per second, in total and to a single host
+    rate: Option<f64>,
+    per_host_rate: Option<f64>,
+    egress: Egress,
+    // user:password to try on servers asking for Basic auth
+    credentials: Vec<String>,
+    // content that proxies must pass on unchanged
+    tamper: Option<tamper::Reference>,
+    // a TLS server whose certificate proxies must pass on unchanged
+    pin: Option<tamper::Pin>,
+    // a host of our own and the ports proxies are asked to CONNECT to on it
+    connect_host: Option<String>,
+    connect_ports: Vec<u16>,
+    // our public addresses, as seen by the servers being verified
+    gateway: Vec<String>,
 }
 
-// XXX: 1. Verify "CONNECT" Ports
-//      2. save vanilla and traceable bits
+// special purpose networks (RFC 6890) that are skipped unless asked for.
+// private networks are left alone since scanning a LAN is the common case.
+static RESERVED_NETWORKS: &'static [&'static str] = &["0.0.0.0/8",
+                                                      "100.64.0.0/10",
+                                                      "127.0.0.0/8",
+                                                      "169.254.0.0/16",
+                                                      "192.0.0.0/24",
+                                                      "192.0.2.0/24",
+                                                      "198.18.0.0/15",
+                                                      "198.51.100.0/24",
+                                                      "203.0.113.0/24",
+                                                      "224.0.0.0/4",
+                                                      "240.0.0.0/4"];
+
 macro_rules! io_error {
     ( $( $a:expr ),* ) => {
         IoError::new(IoErrorKind::Other, format!( $( $a ),* ).as_str());
     };
 }
 
+/// The step of a verification a probe belongs to.
+#[derive(Debug, Clone, Copy)]
+enum Check {
+    Sweep,
+    Httpbin,
+    // httpbin again, over TLS
+    TlsHttpbin,
+    // httpbin again, with the credentials to try on the server
+    Credentials(usize),
+    Rule(usize),
+    Reference,
+    // content fetched through the server, compared with --tamper-url
+    Tamper,
+    // TLS through a tunnel to --tls-host
+    Interception,
+    // a request bound to fail, for the error page the server makes
+    Fingerprint,
+    // a tunnel to one of --connect-ports on --connect-host
+    ConnectPort(usize),
+    Socks(usize),
+}
+
+/// Results gathered for a server being verified.
+struct Verification {
+    pending: usize,
+    // how anonymous the server is, if it is an HTTP proxy
+    http: Option<Anonymity>,
+    // the server takes HTTP over TLS only
+    tls: bool,
+    // the auth scheme the server asks for, and credentials it accepts
+    auth: Option<String>,
+    credentials: Option<String>,
+    // the server changed the content of --tamper-url
+    tampered: bool,
+    // the server presented another certificate for --tls-host
+    intercepted: bool,
+    // responses the server made itself and those it passed on, to tell its
+    // software by
+    made: Vec<http::Response>,
+    passed: Vec<http::Response>,
+    // which of --connect-ports the server opened a tunnel to
+    connect_ports: Vec<bool>,
+    matched: Vec<bool>,
+    // how long each SOCKS check that passed took
+    socks: Vec<Option<Duration>>,
+    lag: Option<Duration>,
+    // some checks were cut short by the deadline
+    expired: bool,
+}
+
+enum Finding {
+    Verified(db_api::ProxyServer),
+    // the server as it is known in the database, by address or hostname
+    Failed(String, u16),
+}
+
+/// Keeps track of which targets, numbered in the order they are taken from
+/// the source, are still being worked on.
+struct Tracker {
+    next: u64,
+    in_flight: HashMap<(Ipv4Addr, u16), u64>,
+    unfinished: BTreeSet<u64>,
+}
+
+impl Tracker {
+    fn new() -> Tracker {
+        Tracker {
+            next: 0,
+            in_flight: HashMap::new(),
+            unfinished: BTreeSet::new(),
+        }
+    }
+
+    fn start(&mut self, host: Ipv4Addr, port: u16) {
+        self.in_flight.insert((host, port), self.next);
+        self.unfinished.insert(self.next);
+        self.next += 1;
+    }
+
+    fn finish(&mut self, host: Ipv4Addr, port: u16) {
+        if let Some(seq) = self.in_flight.remove(&(host, port)) {
+            self.unfinished.remove(&seq);
+        }
+    }
+
+    // every target before the watermark is done
+    fn watermark(&self) -> u64 {
+        self.unfinished.iter().next().cloned().unwrap_or(self.next)
+    }
+}
+
+// options a resumed scan takes from its checkpoint unless given again
+static SAVED_OPTIONS: &'static [&'static str] = &["connect-timeout",
+                                                  "timeout",
+                                                  "deadline",
+                                                  "max-response",
+                                                  "workers",
+                                                  "sweep-workers",
+                                                  "rate",
+                                                  "per-host-rate",
+                                                  "httpbin",
+                                                  "reference",
+                                                  "credentials-file",
+                                                  "tamper-url",
+                                                  "tamper-sha256",
+                                                  "tamper-length",
+                                                  "tls-host",
+                                                  "tls-sha256",
+                                                  "connect-host",
+                                                  "connect-ports",
+                                                  "output",
+                                                  "format"];
+
+const CHECKPOINT_INTERVAL: u64 = 30;
+
+// a host that never resolves (RFC 2606), so that proxies answer with an error
+// page of their own
+const FINGERPRINT_REQUEST: &'static str = "GET http://mole.invalid/ HTTP/1.0\r\n\
+                                           Host: mole.invalid\r\n\r\n";
+
 pub fn run_scan(opts: ArgMatches) {
     info!("starting scanner ...");
-    let network = iprange::Ipv4Network::from_str(opts.value_of("network").unwrap())
-        .expect("you must specify a valid network expression for --network");
-    let dbname = opts.value_of("database").unwrap().to_string();
+    // without a database, results only go to --output
+    let db = opts.value_of("database").map(|dbname| match db_api::init_db(dbname) {
+        Ok(db) => {
+            db_api::init_table(db.get().unwrap()).unwrap();
+            db
+        }
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    });
 
-    let ports = opts.values_of("ports").unwrap().collect::<Vec<_>>();
+    let checkpoint_path = opts.value_of("checkpoint").unwrap().to_string();
+    let checkpoint = if opts.is_present("resume") {
+        match checkpoint::Checkpoint::load(&checkpoint_path) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                error!("cannot resume from {}: {}", checkpoint_path, e);
+                process::exit(2);
+            }
+        }
+    } else {
+        let ports = match ports::parse(opts.value_of("ports").unwrap()) {
+            Ok(ports) => ports,
+            Err(e) => {
+                error!("--ports: {}", e);
+                process::exit(2);
+            }
+        };
+        let mut targets = Vec::new();
+        if let Some(values) = opts.values_of("network") {
+            targets.extend(values.map(|x| x.to_string()));
+        }
+        if let Some(path) = opts.value_of("targets-file") {
+            targets.extend(read_networks(path).expect("cannot read targets from --targets-file"));
+        }
+        let excluded = load_exclusions(&opts, db.as_ref());
+        let mut hosts = iprange::Ipv4RangeSet::new();
+        let mut services = Vec::new();
+        let mut names = Vec::new();
+        for x in targets {
+            match targets::Target::from_str(&x) {
+                Ok(targets::Target::Network(network)) => {
+                    let (first, last) = if opts.is_present("include-reserved") {
+                        network.bounds()
+                    } else {
+                        network.host_bounds()
+                    };
+                    hosts.insert(first, last);
+                }
+                Ok(targets::Target::Range(first, last)) => hosts.insert(first, last),
+                Ok(targets::Target::Service(host, ports)) => {
+                    if !excluded.contains(host) {
+                        services.extend(ports.into_iter().map(|port| (u32::from(host), port)));
+                    }
+                }
+                Ok(targets::Target::Name(name, own_ports)) => {
+                    names.push((name, own_ports.unwrap_or(ports.clone())));
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(2);
+                }
+            }
+        }
+        hosts.remove_all(&excluded);
 
-    // prepare workers' data
-    let mut servers = Vec::new();
-    for (ip, port) in iproduct!(network.iter(), ports.iter()) {
-        let port = port.parse::<u16>().expect("one of ports is not a number");
-        servers.push((ip, port));
+        // hostnames are resolved once, the addresses are kept in the checkpoint
+        let mut hostnames = Vec::new();
+        if !names.is_empty() {
+            let resolved = load_resolver(&opts)
+                .resolve_all(names.iter().map(|&(ref name, _)| name.clone()).collect());
+            for (name, ports) in names {
+                match resolved.get(&name) {
+                    Some(ip) if excluded.contains(*ip) => {
+                        debug!("skipping {} since {} is excluded.", name, ip)
+                    }
+                    Some(ip) => {
+                        for port in ports {
+                            services.push((u32::from(*ip), port));
+                            hostnames.push((u32::from(*ip), port, name.clone()));
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+        let seed = match opts.value_of("seed") {
+            Some(x) => x.parse::<u64>().expect("you must specify a number for --seed"),
+            None => random_seed(),
+        };
+        let mut options = BTreeMap::new();
+        for name in SAVED_OPTIONS {
+            if let Some(x) = opts.value_of(name) {
+                options.insert(name.to_string(), x.to_string());
+            }
+        }
+        checkpoint::Checkpoint::new(&hosts, ports, services, hostnames, seed, options)
+    };
+
+    let space = targets::TargetSpace::new(&checkpoint.hosts(),
+                                          checkpoint.ports.clone(),
+                                          &checkpoint.services);
+    info!("{} targets to scan, shuffled with seed {}.", space.len(), checkpoint.seed);
+    let mut servers = targets::ShuffledTargets::new(space, checkpoint.seed);
+    let mut hostnames: HashMap<(Ipv4Addr, u16), Vec<String>> = HashMap::new();
+    for &(ip, port, ref name) in &checkpoint.hostnames {
+        hostnames.entry((Ipv4Addr::from(ip), port)).or_insert_with(Vec::new).push(name.clone());
+    }
+    if checkpoint.position > 0 {
+        info!("resuming from target #{}.", checkpoint.position);
+        servers.nth(checkpoint.position as usize - 1);
     }
-    let db = db_api::init_db(&dbname).unwrap();
-    db_api::init_table(db.get().unwrap()).unwrap();
 
-    scan(db,
-         servers.into_iter(),
-         ScanOptions {
-             gateway: get_gateway_ip(),
-             reference: opts.value_of("reference").unwrap().to_string(),
-             httpbin: opts.value_of("httpbin").unwrap().to_string(),
-             timeout: Duration::new(opts.value_of("timeout")
+    let mut egress = load_egress(&opts);
+    let scan_opts = {
+        let option = |name: &str| saved_option(&opts, &checkpoint.options, name);
+        let connect_timeout = Duration::new(option("connect-timeout")
+                                                .unwrap()
+                                                .parse::<u64>()
+                                                .expect("you must specify a number for \
+                                                         --connect-timeout"),
+                                            0);
+        let timeout = Duration::new(option("timeout").unwrap().parse::<u64>().unwrap(), 0);
+        ScanOptions {
+            gateway: get_gateway_ips(&mut egress, connect_timeout, timeout),
+            reference: option("reference").unwrap(),
+            httpbin: option("httpbin").unwrap(),
+            connect_timeout: connect_timeout,
+            timeout: timeout,
+            deadline: Duration::new(option("deadline")
                                         .unwrap()
                                         .parse::<u64>()
-                                        .unwrap(),
+                                        .expect("you must specify a number for --deadline"),
                                     0),
-             num_workers: opts.value_of("workers")
-                 .unwrap()
-                 .parse::<usize>()
-                 .expect("you must specify a number for --workers"),
-         });
+            max_response: option("max-response")
+                .unwrap()
+                .parse::<usize>()
+                .expect("you must specify a number for --max-response"),
+            num_workers: option("workers")
+                .unwrap()
+                .parse::<usize>()
+                .expect("you must specify a number for --workers"),
+            // only ports that accept a tcp connection are passed on to verification
+            num_sweepers: Some(option("sweep-workers")
+                .unwrap()
+                .parse::<usize>()
+                .expect("you must specify a number for --sweep-workers")),
+            rate: option("rate").map(|x| load_rate("rate", &x)),
+            per_host_rate: option("per-host-rate").map(|x| load_rate("per-host-rate", &x)),
+            tamper: load_tamper(option("tamper-url"),
+                                option("tamper-sha256"),
+                                option("tamper-length"),
+                                &mut egress,
+                                connect_timeout,
+                                timeout),
+            pin: load_pin(option("tls-host"),
+                          option("tls-sha256"),
+                          &mut egress,
+                          connect_timeout,
+                          timeout),
+            connect_host: option("connect-host"),
+            connect_ports: load_connect_ports(&option("connect-ports").unwrap()),
+            egress: egress,
+            credentials: load_credentials(option("credentials-file")),
+        }
+    };
+
+    let output = saved_option(&opts, &checkpoint.options, "output").map(|path| {
+        let format = saved_option(&opts, &checkpoint.options, "format").unwrap();
+        let format = output::Format::from_str(&format).expect("unknown format for --format");
+        // a resumed scan adds to what it has written so far
+        let mut output = output::Output::open(&path, format, opts.is_present("resume"))
+            .expect("cannot open file specified by --output");
+        output.include_credentials(opts.is_present("output-credentials"));
+        output
+    });
+    if db.is_none() && output.is_none() {
+        error!("either --database or --output is required.");
+        process::exit(2);
+    }
+
+    signal::catch_interrupt();
+    scan(db,
+         output,
+         servers,
+         hostnames,
+         HashMap::new(),
+         scan_opts,
+         Some((checkpoint, checkpoint_path.clone())));
+    if signal::interrupts() > 0 {
+        warn!("scan interrupted. continue it with --resume.");
+        process::exit(130);
+    }
+    let _ = fs::remove_file(&checkpoint_path);
     info!("scan completed.");
 }
 
@@ -77,23 +412,125 @@ pub fn run_verify(opts: ArgMatches) {
     let db = db_api::init_db(&dbname).unwrap();
 
     db_api::init_table(db.get().unwrap()).unwrap();
-    let servers = db_api::get_proxy_servers(db.get().unwrap()).unwrap();
-    scan(db,
-         servers.into_iter().map(|x| (x.host, x.port)),
+    let excluded = load_exclusions(&opts, Some(&db));
+    let mut servers = Vec::new();
+    let mut names = Vec::new();
+    let mut stored = HashMap::new();
+    // every row an address is known by, since names can resolve to the
+    // address of another row; the address is verified once for all of them
+    let mut hostnames: HashMap<(Ipv4Addr, u16), Vec<String>> = HashMap::new();
+    for x in db_api::get_proxy_servers(db.get().unwrap()).unwrap() {
+        if let Some(credentials) = x.credentials {
+            stored.insert((x.host.clone(), x.port), credentials);
+        }
+        match Ipv4Addr::from_str(&x.host) {
+            Ok(ip) => {
+                let identities = hostnames.entry((ip, x.port)).or_insert_with(Vec::new);
+                if identities.is_empty() {
+                    servers.push((ip, x.port));
+                }
+                identities.push(x.host);
+            }
+            Err(_) => names.push((x.host, x.port)),
+        }
+    }
+
+    // servers imported by name are looked up again on every verification
+    if !names.is_empty() {
+        let resolved = load_resolver(&opts)
+            .resolve_all(names.iter().map(|&(ref name, _)| name.clone()).collect());
+        for (name, port) in names {
+            match resolved.get(&name) {
+                Some(ip) => {
+                    let identities = hostnames.entry((*ip, port)).or_insert_with(Vec::new);
+                    if identities.is_empty() {
+                        servers.push((*ip, port));
+                    }
+                    identities.push(name);
+                }
+                None => {
+                    let result = db_api::ProxyServer::new(&name, port, None, None, None)
+                        .and_then(|server| db_api::disable_proxy(db.get().unwrap(), server));
+                    if let Err(e) = result {
+                        error!("cannot save verification result: {}", e);
+                    }
+                }
+            }
+        }
+    }
+    let servers = servers.into_iter()
+        .filter(|&(ip, port)| if excluded.contains(ip) {
+            debug!("skipping excluded server {}:{}.", ip, port);
+            false
+        } else {
+            true
+        })
+        .collect::<Vec<_>>();
+    // credentials that worked before are tried first
+    let credentials = hostnames.iter()
+        .filter_map(|(&(ip, port), identities)| {
+            identities.iter()
+                .filter_map(|host| stored.get(&(host.clone(), port)))
+                .next()
+                .map(|x| ((ip, port), x.clone()))
+        })
+        .collect::<HashMap<(Ipv4Addr, u16), String>>();
+    let mut egress = load_egress(&opts);
+    let connect_timeout = Duration::new(opts.value_of("connect-timeout")
+                                            .unwrap()
+                                            .parse::<u64>()
+                                            .expect("you must specify a number for \
+                                                     --connect-timeout"),
+                                        0);
+    let timeout = Duration::new(opts.value_of("timeout").unwrap().parse::<u64>().unwrap(), 0);
+    signal::catch_interrupt();
+    scan(Some(db),
+         None,
+         servers.into_iter(),
+         hostnames,
+         credentials,
          ScanOptions {
-             gateway: get_gateway_ip(),
+             gateway: get_gateway_ips(&mut egress, connect_timeout, timeout),
              reference: opts.value_of("reference").unwrap().to_string(),
              httpbin: opts.value_of("httpbin").unwrap().to_string(),
-             timeout: Duration::new(opts.value_of("timeout")
-                                        .unwrap()
-                                        .parse::<u64>()
-                                        .unwrap(),
-                                    0),
+             connect_timeout: connect_timeout,
+             timeout: timeout,
+             deadline: Duration::new(opts.value_of("deadline")
+                                         .unwrap()
+                                         .parse::<u64>()
+                                         .expect("you must specify a number for --deadline"),
+                                     0),
+             max_response: opts.value_of("max-response")
+                 .unwrap()
+                 .parse::<usize>()
+                 .expect("you must specify a number for --max-response"),
              num_workers: opts.value_of("workers")
                  .unwrap()
                  .parse::<usize>()
                  .expect("you must specify a number for --workers"),
-         });
+             num_sweepers: None,
+             rate: opts.value_of("rate").map(|x| load_rate("rate", x)),
+             per_host_rate: opts.value_of("per-host-rate").map(|x| load_rate("per-host-rate", x)),
+             tamper: load_tamper(opts.value_of("tamper-url").map(String::from),
+                                 opts.value_of("tamper-sha256").map(String::from),
+                                 opts.value_of("tamper-length").map(String::from),
+                                 &mut egress,
+                                 connect_timeout,
+                                 timeout),
+             pin: load_pin(opts.value_of("tls-host").map(String::from),
+                           opts.value_of("tls-sha256").map(String::from),
+                           &mut egress,
+                           connect_timeout,
+                           timeout),
+             connect_host: opts.value_of("connect-host").map(String::from),
+             connect_ports: load_connect_ports(opts.value_of("connect-ports").unwrap()),
+             egress: egress,
+             credentials: load_credentials(opts.value_of("credentials-file").map(String::from)),
+         },
+         None);
+    if signal::interrupts() > 0 {
+        process::exit(130);
+    }
     info!("verification completed.");
 }
 
@@ -107,25 +544,27 @@ pub fn run_import(opts: ArgMatches) -> IoResult<()> {
     let mut content = String::new();
     try!(file.read_to_string(&mut content));
 
-    let servers: Vec<(&str, u16)> = content.lines()
-        .map(|x| {
-            let mut s = x.split(':');
-            match (s.next(), s.next()) {
-                (Some(host), Some(port)) => {
-                    match port.parse() {
-                        Ok(port) => Some((host, port)),
-                        _ => None,
-                    }
+    let mut servers: Vec<(&str, u16)> = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // the port part takes the same syntax as --ports
+        let mut s = line.splitn(2, ':');
+        match (s.next(), s.next()) {
+            (Some(host), Some(spec)) => {
+                match ports::parse(spec) {
+                    Ok(ports) => servers.extend(ports.into_iter().map(|port| (host, port))),
+                    Err(e) => warn!("line {}: {}", n + 1, e),
                 }
-                _ => None,
             }
-        })
-        .filter(|x| x.is_some())
-        .map(|x| x.unwrap())
-        .collect();
+            _ => warn!("line {}: missing port in `{}'", n + 1, line),
+        }
+    }
     for (host, port) in servers {
         let conn = db.get().unwrap();
-        if let Ok(x) = db_api::ProxyServer::new(host, port, None, None, None, None) {
+        if let Ok(x) = db_api::ProxyServer::new(host, port, None, None, None) {
             info!("adding server {}.", x);
             db_api::add_proxy(conn, x).unwrap();
         } else {
@@ -135,189 +574,1108 @@ pub fn run_import(opts: ArgMatches) -> IoResult<()> {
     Ok(())
 }
 
-fn http_request(server: Ipv4Addr, port: u16, content: &str, timeout: Duration) -> IoResult<String> {
-    let tcp = try!(TcpBuilder::new_v4());
-    let mut stream = try!(tcp.connect((server, port)));
-    try!(stream.set_read_timeout(Some(timeout)));
-    try!(stream.set_write_timeout(Some(timeout)));
-    let mut resp = String::new();
-    let _ = stream.write(content.as_bytes());
-    let _ = stream.read_to_string(&mut resp);
-    Ok(resp)
-}
+pub fn run_exclude(opts: ArgMatches) {
+    let dbname = opts.value_of("database").unwrap().to_string();
+    let db = db_api::init_db(&dbname).unwrap();
+    db_api::init_table(db.get().unwrap()).unwrap();
 
-fn get_gateway_ip() -> Option<String> {
-    debug!("determing your gateway ip address ...");
-    let client = hyper::client::Client::new();
-    let mut resp = client.get("http://ifconfig.in/").send().unwrap();
-    let ip = if resp.status == hyper::Ok {
-        let mut ip = String::new();
-        if let Err(_) = resp.read_to_string(&mut ip) {
-            None
+    if opts.is_present("list") {
+        for network in db_api::get_exclusions(db.get().unwrap()).unwrap() {
+            println!("{}", network);
+        }
+        return;
+    }
+    for network in opts.values_of("network").unwrap() {
+        if let Err(e) = iprange::Ipv4Network::from_str(network) {
+            warn!("{}", e);
+            continue;
+        }
+        let conn = db.get().unwrap();
+        if opts.is_present("delete") {
+            db_api::delete_exclusion(conn, network).unwrap();
+            info!("{} removed from do-not-scan list.", network);
         } else {
-            info!("found gateway ip {}. tracing detection enabled.", ip);
-            Some(ip)
+            db_api::add_exclusion(conn, network).unwrap();
+            info!("{} added to do-not-scan list.", network);
         }
+    }
+}
+
+// reads one network per line, from stdin if `path` is "-"
+fn read_networks(path: &str) -> IoResult<Vec<String>> {
+    let mut content = String::new();
+    if path == "-" {
+        try!(io::stdin().read_to_string(&mut content));
     } else {
-        None
+        let mut file = try!(File::open(&Path::new(path)));
+        try!(file.read_to_string(&mut content));
+    }
+    Ok(content.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| x.to_string())
+        .collect())
+}
+
+// combines the do-not-scan table, --exclude, --exclude-file and reserved networks
+fn load_exclusions(opts: &ArgMatches, db: Option<&db_api::Pool>) -> iprange::Ipv4RangeSet {
+    let mut networks = match db {
+        Some(db) => db_api::get_exclusions(db.get().unwrap()).unwrap(),
+        None => Vec::new(),
     };
-    if ip.is_none() {
+    if let Some(values) = opts.values_of("exclude") {
+        networks.extend(values.map(|x| x.to_string()));
+    }
+    if let Some(path) = opts.value_of("exclude-file") {
+        networks.extend(read_networks(path).expect("cannot read networks from --exclude-file"));
+    }
+    if !opts.is_present("include-reserved") {
+        networks.extend(RESERVED_NETWORKS.iter().map(|x| x.to_string()));
+    }
+
+    let mut excluded = iprange::Ipv4RangeSet::new();
+    for x in networks {
+        match iprange::Ipv4Network::from_str(&x) {
+            Ok(network) => excluded.insert_network(&network),
+            Err(e) => {
+                error!("cannot exclude {}: {}", x, e);
+                process::exit(2);
+            }
+        }
+    }
+    excluded
+}
+
+fn load_resolver(opts: &ArgMatches) -> resolver::Resolver {
+    let server = opts.value_of("resolver").map(|x| {
+        SocketAddr::from_str(x)
+            .or(Ipv4Addr::from_str(x).map(|ip| SocketAddr::V4(SocketAddrV4::new(ip, 53))))
+            .expect("you must specify an ip address or ip:port for --resolver")
+    });
+    let timeout = opts.value_of("timeout").unwrap().parse::<u64>().unwrap();
+    resolver::Resolver::new(server, Duration::new(timeout, 0))
+}
+
+// an option given on the command line wins over the one saved in a checkpoint
+fn saved_option(opts: &ArgMatches,
+                saved: &BTreeMap<String, String>,
+                name: &str)
+                -> Option<String> {
+    match saved.get(name) {
+        Some(x) if opts.occurrences_of(name) == 0 => Some(x.clone()),
+        _ => opts.value_of(name).map(|x| x.to_string()),
+    }
+}
+
+fn random_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    now.as_secs() ^ ((now.subsec_nanos() as u64) << 32)
+}
+
+// asks ifconfig.in for our address from the next source address of `egress`
+fn get_gateway_ip_from(egress: &mut Egress,
+                       connect_timeout: Duration,
+                       timeout: Duration)
+                       -> IoResult<String> {
+    let addr = try!(try!(("ifconfig.in", 80).to_socket_addrs())
+        .find(|x| x.is_ipv4())
+        .ok_or(io_error!("cannot resolve ifconfig.in")));
+    let mut stream = try!(egress.connect_blocking(&addr, connect_timeout, timeout));
+    try!(stream.write_all(b"GET / HTTP/1.0\r\nHost: ifconfig.in\r\n\r\n"));
+    let mut resp = String::new();
+    try!(stream.read_to_string(&mut resp));
+    if !resp.starts_with("HTTP/1.1 200") && !resp.starts_with("HTTP/1.0 200") {
+        return Err(io_error!("ifconfig.in returns {}", resp.lines().next().unwrap_or("")));
+    }
+    let body = try!(resp.splitn(2, "\r\n\r\n").nth(1).ok_or(io_error!("malformed HTTP response")));
+    Ok(body.trim().to_string())
+}
+
+// every source address may leave through a different gateway
+fn get_gateway_ips(egress: &mut Egress,
+                   connect_timeout: Duration,
+                   timeout: Duration)
+                   -> Vec<String> {
+    let mut ips = Vec::new();
+    for _ in 0..cmp::max(egress.ips().len(), 1) {
+        match get_gateway_ip_from(egress, connect_timeout, timeout) {
+            Ok(ip) => ips.push(ip),
+            Err(e) => warn!("cannot determine gateway ip: {}", e),
+        }
+    }
+    if ips.is_empty() {
         warn!("no gateway address found. tracing detection disabled.");
+    } else {
+        info!("found gateway ip {}. tracing detection enabled.", ips.join(", "));
+    }
+    ips
+}
+
+// one user:password per line
+fn load_credentials(path: Option<String>) -> Vec<String> {
+    let path = match path {
+        Some(path) => path,
+        None => return Vec::new(),
+    };
+    let mut content = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut content)) {
+        error!("cannot read credentials from {}: {}", path, e);
+        process::exit(1);
+    }
+    content.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+// the content of --tamper-url, fetched directly to learn what is not given
+fn load_tamper(url: Option<String>,
+               sha256: Option<String>,
+               length: Option<String>,
+               egress: &mut Egress,
+               connect_timeout: Duration,
+               timeout: Duration)
+               -> Option<tamper::Reference> {
+    let url = match url {
+        Some(url) => url,
+        None => return None,
+    };
+    let length = length.map(|x| {
+        x.parse::<usize>().expect("you must specify a number for --tamper-length")
+    });
+    let sha256 = sha256.as_ref().map(|x| x.as_str());
+    let mut reference = match tamper::Reference::new(&url, sha256, length) {
+        Ok(reference) => reference,
+        Err(e) => {
+            error!("--tamper-url: {}", e);
+            process::exit(2);
+        }
+    };
+    match fetch_directly(&reference, egress, connect_timeout, timeout) {
+        Ok(resp) => reference.learn(&resp),
+        Err(e) => warn!("cannot fetch {} directly: {}", url, e),
+    }
+    if !reference.is_known() {
+        warn!("content of {} unknown. tampering detection disabled.", url);
+        return None;
     }
-    ip
+    info!("tampering detection enabled with {}.", url);
+    Some(reference)
+}
+
+fn fetch_directly(reference: &tamper::Reference,
+                  egress: &mut Egress,
+                  connect_timeout: Duration,
+                  timeout: Duration)
+                  -> IoResult<http::Response> {
+    let addr = try!(try!((reference.host.as_str(), reference.port).to_socket_addrs())
+        .find(|x| x.is_ipv4())
+        .ok_or(io_error!("cannot resolve {}", reference.host)));
+    let mut stream = try!(egress.connect_blocking(&addr, connect_timeout, timeout));
+    try!(stream.write_all(reference.request(false).as_bytes()));
+    let mut resp = Vec::new();
+    try!(stream.read_to_end(&mut resp));
+    let resp = try!(http::Response::parse(&resp).map_err(|e| io_error!("{}", e)));
+    if resp.status != 200 {
+        return Err(io_error!("{} returns {} {}", reference.host, resp.status, resp.reason));
+    }
+    Ok(resp)
+}
+
+// the certificate of --tls-host, fetched directly unless pinned
+fn load_pin(spec: Option<String>,
+            sha256: Option<String>,
+            egress: &mut Egress,
+            connect_timeout: Duration,
+            timeout: Duration)
+            -> Option<tamper::Pin> {
+    let spec = match spec {
+        Some(spec) => spec,
+        None => return None,
+    };
+    let mut pin = match tamper::Pin::new(&spec, sha256.as_ref().map(|x| x.as_str())) {
+        Ok(pin) => pin,
+        Err(e) => {
+            error!("--tls-host: {}", e);
+            process::exit(2);
+        }
+    };
+    if !pin.is_known() {
+        match fetch_certificate(&pin, egress, connect_timeout, timeout) {
+            Ok(sha256) => pin.learn(sha256),
+            Err(e) => warn!("cannot fetch certificate of {} directly: {}", spec, e),
+        }
+    }
+    if !pin.is_known() {
+        warn!("certificate of {} unknown. tls interception detection disabled.", spec);
+        return None;
+    }
+    info!("tls interception detection enabled with {}.", spec);
+    Some(pin)
+}
+
+// the SHA-256 fingerprint of the certificate a TLS server presents
+fn fetch_certificate(pin: &tamper::Pin,
+                     egress: &mut Egress,
+                     connect_timeout: Duration,
+                     timeout: Duration)
+                     -> IoResult<Vec<u8>> {
+    let addr = try!(try!((pin.host.as_str(), pin.port).to_socket_addrs())
+        .find(|x| x.is_ipv4())
+        .ok_or(io_error!("cannot resolve {}", pin.host)));
+    let stream = try!(egress.connect_blocking(&addr, connect_timeout, timeout));
+    let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| io_error!("{}", e)));
+    ctx.set_verify(SSL_VERIFY_NONE, None);
+    let stream = try!(SslStream::connect(&ctx, stream).map_err(|e| io_error!("{}", e)));
+    let certificate = stream.ssl().peer_certificate();
+    certificate.and_then(|x| x.fingerprint(hash::Type::SHA256))
+        .ok_or(io_error!("{} presents no certificate", pin.host))
 }
 
-fn verify_server(host: Ipv4Addr, port: u16, opts: &ScanOptions) -> IoResult<db_api::ProxyServer> {
-    trace!("connecting {:?}:{:?} ...", host, port);
-    // verify regular proxy request
-    let request = format!("GET http://{host}/headers HTTP/1.0\r\n\
-                           Host: {host}\r\n\r\n",
-                          host = opts.httpbin);
-    let resp = try!(http_request(host, port, request.as_str(), opts.timeout));
-    let body = try!(resp.splitn(2, "\r\n\r\n")
-        .last()
-        .ok_or(io_error!("malformed HTTP response")));
-    let data: Value = try!(serde_json::from_str(body)
+fn load_connect_ports(spec: &str) -> Vec<u16> {
+    match ports::parse(spec) {
+        Ok(ports) => ports,
+        Err(e) => {
+            error!("--connect-ports: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
+fn load_rate(name: &str, value: &str) -> f64 {
+    match ratelimit::parse_rate(value) {
+        Ok(rate) => rate,
+        Err(e) => {
+            error!("--{}: {}", name, e);
+            process::exit(2);
+        }
+    }
+}
+
+fn load_egress(opts: &ArgMatches) -> Egress {
+    let ips = opts.values_of("source-ip")
+        .map(|values| {
+            values.map(|x| Ipv4Addr::from_str(x).expect("you must specify an ip address for --source-ip"))
+                .collect::<Vec<Ipv4Addr>>()
+        })
+        .unwrap_or(Vec::new());
+    let ports = match opts.value_of("source-ports").map(ports::parse) {
+        Some(Ok(ports)) => ports,
+        Some(Err(e)) => {
+            error!("--source-ports: {}", e);
+            process::exit(2);
+        }
+        None => Vec::new(),
+    };
+    Egress::new(ips, ports)
+}
+
+fn http_probe(host: Ipv4Addr,
+              port: u16,
+              request: &str,
+              tls: bool,
+              deadline: Option<Instant>)
+              -> Probe {
+    let mut probe = raw_probe(host, port, request.as_bytes(), deadline);
+    probe.tls = tls;
+    probe
+}
+
+fn raw_probe(host: Ipv4Addr, port: u16, payload: &[u8], deadline: Option<Instant>) -> Probe {
+    Probe {
+        addr: SocketAddr::V4(SocketAddrV4::new(host, port)),
+        payload: payload.to_vec(),
+        deadline: deadline,
+        tls: false,
+        tunnel: None,
+    }
+}
+
+// host and port of an --httpbin or --reference, e.g. http://www.baidu.com/
+fn http_destination(spec: &str) -> (String, u16) {
+    let spec = spec.trim_left_matches("http://");
+    let authority = spec.split('/').next().unwrap_or("");
+    let mut parts = authority.splitn(2, ':');
+    let host = parts.next().unwrap_or("").to_string();
+    let port = parts.next().and_then(|x| x.parse::<u16>().ok()).unwrap_or(80);
+    (host, port)
+}
+
+// SOCKS4 connects to an address while SOCKS4A has the server resolve the
+// name; SOCKS5 is tried with every address type it has. A request follows
+// right away so that the server hangs up on us.
+fn socks_checks(opts: &ScanOptions) -> Vec<socks::Check> {
+    let mut checks = vec![socks::Check::socks5("SOCKS5"),
+                          socks::Check::socks5_method("SOCKS5_AUTH_NONE", socks::SOCKS5_NO_AUTH),
+                          socks::Check::socks5_method("SOCKS5_AUTH_USERPASS",
+                                                      socks::SOCKS5_USERPASS),
+                          socks::Check::socks5_method("SOCKS5_AUTH_GSSAPI", socks::SOCKS5_GSSAPI)];
+    for spec in &[&opts.httpbin, &opts.reference] {
+        let (host, port) = http_destination(spec);
+        let request = format!("GET / HTTP/1.0\r\nHost: {}\r\n\r\n", host);
+        let request = request.as_bytes();
+        let addrs = (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<SocketAddr>>())
+            .unwrap_or(Vec::new());
+        match addrs.iter().find(|x| x.is_ipv4()) {
+            Some(&SocketAddr::V4(ref addr)) => {
+                let mut payload = socks::socks4_connect(addr);
+                payload.extend_from_slice(request);
+                checks.push(socks::Check::socks4("SOCKS4", payload));
+                let mut payload = socks::socks5_connect(&SocketAddr::V4(*addr));
+                payload.extend_from_slice(request);
+                checks.push(socks::Check::socks5_connect("SOCKS5_CONNECT_IPV4", payload));
+            }
+            _ => warn!("{} has no ipv4 address, SOCKS is checked by name only.", host),
+        }
+        if let Some(addr) = addrs.iter().find(|x| x.is_ipv6()) {
+            let mut payload = socks::socks5_connect(addr);
+            payload.extend_from_slice(request);
+            checks.push(socks::Check::socks5_connect("SOCKS5_CONNECT_IPV6", payload));
+        }
+        let mut payload = socks::socks4a_connect(&host, port);
+        payload.extend_from_slice(request);
+        checks.push(socks::Check::socks4("SOCKS4A", payload));
+        let mut payload = socks::socks5_connect_domain(&host, port);
+        payload.extend_from_slice(request);
+        checks.push(socks::Check::socks5_connect("SOCKS5_CONNECT_DOMAIN", payload));
+    }
+    checks
+}
+
+fn httpbin_request(opts: &ScanOptions) -> String {
+    format!("GET http://{host}/headers HTTP/1.0\r\n\
+             Host: {host}\r\n\r\n",
+            host = opts.httpbin)
+}
+
+// returns how anonymous the proxy is by the headers httpbin received
+fn check_httpbin(resp: Vec<u8>, opts: &ScanOptions) -> IoResult<Anonymity> {
+    let resp = try!(http::Response::parse(&resp).map_err(|e| io_error!("{}", e)));
+    if resp.status != 200 {
+        return Err(io_error!("httpbin returns {} {}", resp.status, resp.reason));
+    }
+    let data: Value = try!(serde_json::from_slice(&resp.body)
         .map_err(|_| io_error!("httpbin returns malformed json")));
     let data = try!(data.find("headers")
         .ok_or(io_error!("httpbin returns incompleted data")));
     let headers = try!(data.as_object()
         .ok_or(io_error!("httpbin returns incompleted data")));
-    let mut traceable = false;
-    if opts.gateway.is_some() {
-        let gateway = opts.gateway.clone().unwrap();
-        for (_, val) in headers {
-            trace!("matching header with gateway ip: {:?} vs {:?}",
-                   val,
-                   gateway);
-            match val {
-                &Value::String(ref x) if x.find(&gateway).is_some() => {
-                    traceable = true;
-                    break;
-                }
-                _ => {}
-            }
-        }
-    }
-    debug!("{:?}:{:?} returns {:?}", host, port, headers);
-    let tags = detect_server(host, port, opts.timeout).ok();
-    let lag = ping_reference(host, port, &opts.reference, opts.timeout);
-    info!("{}/{}: {:?}", host, port, tags);
-    db_api::ProxyServer::new(host.to_string().as_str(),
-                             port,
-                             lag.ok(),
-                             Some(headers.len() == 1),
-                             Some(traceable),
-        tags)
-        .map_err(|e| io_error!("{:?}", e))
-}
-
-fn ping_reference(host: Ipv4Addr,
-                  port: u16,
-                  reference: &str,
-                  timeout: Duration)
-                  -> IoResult<Duration> {
-    let started = Instant::now();
-    let request = format!("GET http://{0} HTTP/1.0\r\nHost: {0}\r\n\r\n", reference);
-    let _ = try!(http_request(host, port, request.as_str(), timeout));
-    Ok(Instant::now() - started)
-}
-
-fn detect_server(host: Ipv4Addr, port: u16, timeout: Duration) -> IoResult<Vec<String>> {
-    let mut tags = Vec::new();
-    let rules = try!(detection::rules());
-    for (tag, request, needle) in rules {
-        let resp = match http_request(host, port, request.as_str(), timeout) {
-            Ok(x) => x,
-            _ => continue,
-        };
-        info!("checking {} for {}/{}: {}", tag, host, port, resp);
-        trace!("detect {}/{}/{} => {}", tag, request, needle, resp);
-        if let Some(_) = resp.find(needle.as_str()) {
-            tags.push(tag)
+    debug!("httpbin returns {:?}", headers);
+    let headers = headers.iter().filter_map(|(name, value)| match *value {
+        Value::String(ref x) => Some((name.as_str(), x.as_str())),
+        _ => None,
+    });
+    Ok(Anonymity::classify(headers, &opts.gateway))
+}
+
+// saves findings to the database and writes verified servers to `output`
+fn start_writer(db: Option<db_api::Pool>,
+                mut output: Option<output::Output>)
+                -> (mpsc::Sender<Finding>, thread::JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel();
+    let writer = thread::spawn(move || {
+        for finding in rx.iter() {
+            if let (&Finding::Verified(ref server), Some(ref mut output)) = (&finding,
+                                                                             output.as_mut()) {
+                if let Err(e) = output.write(server) {
+                    error!("cannot write {} to output: {}", server, e);
+                }
+            }
+            let db = match db {
+                Some(ref db) => db,
+                None => continue,
+            };
+            let conn = match db.get() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("cannot get database connection: {:?}", e);
+                    continue;
+                }
+            };
+            let result = match finding {
+                Finding::Verified(server) => db_api::add_proxy(conn, server),
+                Finding::Failed(host, port) => {
+                    db_api::ProxyServer::new(&host, port, None, None, None)
+                        .and_then(|server| db_api::disable_proxy(conn, server))
+                }
+            };
+            if let Err(e) = result {
+                error!("cannot save verification result: {}", e);
+            }
         }
-    }
-    Ok(tags)
+    });
+    (tx, writer)
 }
 
-fn scan<I>(db: db_api::Pool, servers: I, opts: ScanOptions)
-    where I: 'static + Iterator<Item = (Ipv4Addr, u16)> + std::marker::Send
+// `hostnames` maps the targets that were given by name to their hostnames,
+// all of which the result is saved for, `credentials` the targets to the
+// credentials they accepted before
+fn scan<I>(db: Option<db_api::Pool>,
+           output: Option<output::Output>,
+           servers: I,
+           hostnames: HashMap<(Ipv4Addr, u16), Vec<String>>,
+           credentials: HashMap<(Ipv4Addr, u16), String>,
+           opts: ScanOptions,
+           checkpoint: Option<(checkpoint::Checkpoint, String)>)
+    where I: Iterator<Item = (Ipv4Addr, u16)>
 {
-    let total_servers = match servers.size_hint() {
-        (_, Some(high)) => Some(high),
-        _ => None,
+    let progress = Arc::new(progress::Progress::new(servers.size_hint().1.map(|x| x as u64)));
+    let rules = detection::rules().map(|x| x.collect::<Vec<_>>()).ok();
+    let num_rules = rules.as_ref().map_or(0, |x| x.len());
+    let httpbin = httpbin_request(&opts);
+    let reference = format!("GET http://{0} HTTP/1.0\r\nHost: {0}\r\n\r\n", opts.reference);
+    let socks_checks = socks_checks(&opts);
+    // the names results are saved under
+    let identities = |host: Ipv4Addr, port| {
+        hostnames.get(&(host, port)).cloned().unwrap_or(vec![host.to_string()])
+    };
+    // rules and the latency check for a server found to be an HTTP proxy
+    let http_checks = |host, port, deadline, tls, credentials: Option<&str>| {
+        let request = |x: &str| credentials.map_or(x.to_string(), |c| http::authorize(x, c));
+        let mut checks = Vec::new();
+        if let Some(ref rules) = rules {
+            for (n, &(_, ref rule, _)) in rules.iter().enumerate() {
+                checks.push(((host, port, Check::Rule(n), deadline),
+                             http_probe(host, port, &request(rule), tls, deadline)));
+            }
+        }
+        checks.push(((host, port, Check::Reference, deadline),
+                     http_probe(host, port, &request(&reference), tls, deadline)));
+        checks.push(((host, port, Check::Fingerprint, deadline),
+                     http_probe(host, port, &request(FINGERPRINT_REQUEST), tls, deadline)));
+        if let Some(ref connect_host) = opts.connect_host {
+            for (n, connect_port) in opts.connect_ports.iter().enumerate() {
+                // a request follows so that whatever is on the port hangs up on us
+                let tunnel = http::connect(connect_host, *connect_port, "GET / HTTP/1.0\r\n\r\n");
+                checks.push(((host, port, Check::ConnectPort(n), deadline),
+                             http_probe(host, port, &request(&tunnel), tls, deadline)));
+            }
+        }
+        if let Some(ref tamper) = opts.tamper {
+            checks.push(((host, port, Check::Tamper, deadline),
+                         http_probe(host, port, &request(&tamper.request(true)), tls, deadline)));
+        }
+        // a tunnel within TLS to the server itself is not supported
+        if let (Some(ref pin), false) = (opts.pin.as_ref(), tls) {
+            let mut probe = http_probe(host, port, &pin.request(), false, deadline);
+            probe.tunnel = Some(request(&pin.tunnel_request()).into_bytes());
+            checks.push(((host, port, Check::Interception, deadline), probe));
+        }
+        checks
     };
-    let queue = Arc::new(Mutex::new(servers));
-    let mut workers = Vec::new();
+    // the credentials to try on a server, those it accepted before first
+    let candidates = |host, port| {
+        let mut candidates = credentials.get(&(host, port))
+            .cloned()
+            .into_iter()
+            .collect::<Vec<_>>();
+        for x in &opts.credentials {
+            if !candidates.contains(x) {
+                candidates.push(x.clone());
+            }
+        }
+        candidates
+    };
+    let (writer, writer_thread) = start_writer(db, output);
+
+    let tracker = Arc::new(Mutex::new(Tracker::new()));
+    let servers = {
+        let (httpbin, tracker) = (httpbin.clone(), tracker.clone());
+        let first_check = if opts.num_sweepers.is_some() {
+            Check::Sweep
+        } else {
+            Check::Httpbin
+        };
+        servers.map(move |(host, port)| {
+            tracker.lock().unwrap().start(host, port);
+            // a sweep connects only
+            let payload = match first_check {
+                Check::Sweep => "",
+                _ => httpbin.as_str(),
+            };
+            ((host, port, first_check, None), http_probe(host, port, payload, false, None))
+        })
+    };
+
+    let reporter = progress::Reporter::start(progress.clone());
 
-    for _ in 0..opts.num_workers {
-        let (db, opts, queue) = (db.clone(), opts.clone(), queue.clone());
-        let worker = thread::spawn(move || {
+    // in-flight verifications get this long to finish once interrupted
+    let grace = opts.deadline;
+    // signals caught before the scan started are not for it
+    let interrupted = signal::interrupts();
+
+    // saves the scan position now and then, and once more on the way out.
+    // a second signal gives up on shutting down gracefully.
+    let (guard_tx, guard_rx) = mpsc::channel();
+    let guard = {
+        let tracker = tracker.clone();
+        let mut checkpoint = checkpoint;
+        let base = checkpoint.as_ref().map_or(0, |&(ref x, _)| x.position);
+        thread::spawn(move || {
+            let mut saved = Instant::now();
+            let mut warned = false;
             loop {
-                let (host, port) = if let Ok(mut queue) = queue.lock() {
-                    match queue.next() {
-                        Some(x) => x,
-                        _ => return,
+                thread::sleep(Duration::from_millis(100));
+                let stopped = guard_rx.try_recv().is_ok();
+                let interrupts = signal::interrupts() - interrupted;
+                if interrupts == 1 && !warned {
+                    warn!("shutting down, waiting up to {}s for probes in flight. \
+                           interrupt again to quit now.",
+                          grace.as_secs());
+                    warned = true;
+                }
+                if let Some((ref mut checkpoint, ref path)) = checkpoint {
+                    if stopped || interrupts > 1 ||
+                       saved.elapsed() > Duration::new(CHECKPOINT_INTERVAL, 0) {
+                        checkpoint.position = base + tracker.lock().unwrap().watermark();
+                        match checkpoint.save(path) {
+                            Ok(_) => debug!("checkpoint saved at target #{}.", checkpoint.position),
+                            Err(e) => error!("cannot save checkpoint to {}: {}", path, e),
+                        }
+                        saved = Instant::now();
                     }
-                } else {
-                    // lock has been poisioned. we quit here.
+                }
+                if interrupts > 1 {
+                    warn!("forced to quit.");
+                    process::exit(130);
+                }
+                if stopped {
                     return;
-                };
-                match verify_server(host, port, &opts) {
-                    Ok(server) => {
-                        // XXX: Get rid of this unwrap, 'cause it will happen in runtime
-                        let conn = db.get().unwrap();
-                        db_api::add_proxy(conn, server).unwrap();
+                }
+            }
+        })
+    };
+
+    let mut verifications: HashMap<(Ipv4Addr, u16), Verification> = HashMap::new();
+    let engine = Engine::new(opts.num_sweepers.unwrap_or(opts.num_workers),
+                             opts.num_workers,
+                             opts.connect_timeout,
+                             opts.timeout,
+                             opts.max_response,
+                             opts.rate,
+                             opts.per_host_rate,
+                             opts.egress.clone());
+    info!("engine started (sweep = {:?}, verify = {}).",
+          opts.num_sweepers,
+          opts.num_workers);
+    let result = engine.run_until(servers, |(host, port, check, deadline), reply: Reply| {
+        let mut followups = Vec::new();
+        let mut expired = false;
+        if let Err(ref e) = reply.result {
+            match e.kind() {
+                IoErrorKind::TimedOut => {
+                    expired = deadline.map_or(false, |x| x <= Instant::now());
+                }
+                IoErrorKind::ConnectionRefused => {}
+                _ => progress.add_error(),
+            }
+        }
+        match check {
+            Check::Sweep => {
+                match reply.result {
+                    Ok(_) => {
+                        debug!("{:?}:{:?} is open.", host, port);
+                        progress.add_open();
+                        followups.push(((host, port, Check::Httpbin, None),
+                                        http_probe(host, port, &httpbin, false, None)));
+                    }
+                    Err(e) => {
+                        trace!("{:?}:{:?} is not connectable: {:?}", host, port, e);
+                        tracker.lock().unwrap().finish(host, port);
+                        progress.add_done();
                     }
+                }
+                return followups;
+            }
+            Check::Httpbin => {
+                // without a sweep, this is the first time the port is seen open
+                if opts.num_sweepers.is_none() && reply.result.is_ok() {
+                    progress.add_open();
+                }
+                let resp = match reply.result {
+                    Ok(resp) => resp,
                     Err(e) => {
                         debug!("error on verifying server {:?}:{:?}: {:?}", host, port, e);
-                        let server = db_api::ProxyServer::new(&host.to_string(),
-                                                              port,
-                                                              None,
-                                                              None,
-                                                              None,
-                                                              None)
-                            .unwrap();
-                        let conn = db.get().unwrap();
-                        db_api::disable_proxy(conn, server).unwrap();
+                        for name in identities(host, port) {
+                            let _ = writer.send(Finding::Failed(name, port));
+                        }
+                        tracker.lock().unwrap().finish(host, port);
+                        progress.add_done();
+                        return followups;
+                    }
+                };
+                // the deadline covers the checks that follow, counted from
+                // the time the server has answered, not from when it was queued
+                let deadline = Some(Instant::now() + opts.deadline);
+                let parsed = http::Response::parse_partial(&resp).ok();
+                let auth = parsed.as_ref().and_then(|x| x.proxy_auth_scheme());
+                let mut http = None;
+                if let Some(ref scheme) = auth {
+                    debug!("{:?}:{:?} asks for {} auth.", host, port, scheme);
+                    if scheme.to_lowercase() == "basic" {
+                        for (n, credentials) in candidates(host, port).iter().enumerate() {
+                            let request = http::authorize(&httpbin, credentials);
+                            followups.push(((host, port, Check::Credentials(n), deadline),
+                                            http_probe(host, port, &request, false, deadline)));
+                        }
+                    }
+                } else {
+                    // servers that do not speak HTTP may still speak SOCKS
+                    match check_httpbin(resp, &opts) {
+                        Ok(result) => {
+                            followups.extend(http_checks(host, port, deadline, false, None));
+                            http = Some(result);
+                        }
+                        Err(e) => {
+                            debug!("{:?}:{:?} is not an HTTP proxy: {:?}", host, port, e);
+                            followups.push(((host, port, Check::TlsHttpbin, deadline),
+                                            http_probe(host, port, &httpbin, true, deadline)));
+                        }
+                    }
+                    for (n, check) in socks_checks.iter().enumerate() {
+                        followups.push(((host, port, Check::Socks(n), deadline),
+                                        raw_probe(host, port, &check.payload, deadline)));
                     }
                 }
+                // a 407 is the proxy's own, anything else comes from httpbin
+                let (made, passed) = if auth.is_some() {
+                    (parsed, None)
+                } else {
+                    (None, parsed)
+                };
+                // this reply is counted off below along with the others
+                verifications.insert((host, port),
+                                     Verification {
+                                         pending: followups.len() + 1,
+                                         http: http,
+                                         tls: false,
+                                         auth: auth,
+                                         credentials: None,
+                                         tampered: false,
+                                         intercepted: false,
+                                         made: made.into_iter().collect(),
+                                         passed: passed.into_iter().collect(),
+                                         connect_ports: vec![false; opts.connect_ports.len()],
+                                         matched: vec![false; num_rules],
+                                         socks: vec![None; socks_checks.len()],
+                                         lag: None,
+                                         expired: false,
+                                     });
             }
-        });
-        workers.push(worker);
-    }
-    info!("workers started (# = {}).", workers.len());
-
-    // start status monitor
-    let (tx, rx) = mpsc::channel();
-    let monitor = thread::spawn(move || {
-        loop {
-            let remain_servers = match queue.lock() {
-                Ok(x) => {
-                    match x.size_hint() {
-                        (_, Some(high)) => Some(high),
-                        _ => None,
+            Check::TlsHttpbin => {
+                let result = reply.result.and_then(|resp| check_httpbin(resp, &opts));
+                if let (Ok(result), Some(verification)) = (result,
+                                                           verifications.get_mut(&(host, port))) {
+                    verification.http = Some(result);
+                    verification.tls = true;
+                    followups.extend(http_checks(host, port, deadline, true, None));
+                    verification.pending += followups.len();
+                }
+            }
+            Check::Credentials(n) => {
+                let result = reply.result.and_then(|resp| check_httpbin(resp, &opts));
+                if let (Ok(result), Some(verification)) = (result,
+                                                           verifications.get_mut(&(host, port))) {
+                    // the first credentials to work are kept
+                    if verification.credentials.is_none() {
+                        let credentials = candidates(host, port).swap_remove(n);
+                        info!("{}/{} accepts credentials for {}.",
+                              host,
+                              port,
+                              credentials.split(':').next().unwrap_or(""));
+                        verification.http = Some(result);
+                        followups.extend(http_checks(host,
+                                                     port,
+                                                     deadline,
+                                                     false,
+                                                     Some(&credentials)));
+                        verification.pending += followups.len();
+                        verification.credentials = Some(credentials);
                     }
                 }
-                _ => continue,
+            }
+            Check::Rule(n) => {
+                // the page may have been cut at --max-response
+                let resp = reply.result.ok().and_then(|x| http::Response::parse_partial(&x).ok());
+                if let (Some(resp), Some(verification), Some(rules)) =
+                       (resp, verifications.get_mut(&(host, port)), rules.as_ref()) {
+                    let (ref tag, ref request, ref needle) = rules[n];
+                    info!("checking {} for {}/{}: {} {}", tag, host, port, resp.status, resp.reason);
+                    trace!("detect {}/{}/{} => {:?}", tag, request, needle, resp);
+                    verification.matched[n] = detection::matches(&resp, needle);
+                    verification.passed.push(resp);
+                }
+            }
+            Check::Reference => {
+                if let Some(verification) = verifications.get_mut(&(host, port)) {
+                    let elapsed = reply.elapsed;
+                    let resp = reply.result.ok();
+                    verification.lag = resp.as_ref().map(|_| elapsed);
+                    verification.passed
+                        .extend(resp.and_then(|x| http::Response::parse_partial(&x).ok()));
+                }
+            }
+            Check::ConnectPort(n) => {
+                if let (Ok(resp), Some(verification)) = (reply.result,
+                                                         verifications.get_mut(&(host, port))) {
+                    verification.connect_ports[n] = http::is_connected(&resp);
+                }
+            }
+            Check::Fingerprint => {
+                let resp = reply.result.ok().and_then(|x| http::Response::parse_partial(&x).ok());
+                if let (Some(resp), Some(verification)) = (resp,
+                                                           verifications.get_mut(&(host, port))) {
+                    // mole.invalid never resolves, so the proxy answers itself
+                    verification.made.push(resp);
+                }
+            }
+            Check::Tamper => {
+                let resp = reply.result.ok().and_then(|x| http::Response::parse(&x).ok());
+                if let (Some(resp), Some(verification), Some(tamper)) =
+                       (resp, verifications.get_mut(&(host, port)), opts.tamper.as_ref()) {
+                    verification.tampered = tamper.is_tampered(&resp);
+                }
+            }
+            Check::Interception => {
+                let pin = opts.pin.as_ref();
+                if let (Some(certificate), Some(verification), Some(pin)) =
+                       (reply.certificate, verifications.get_mut(&(host, port)), pin) {
+                    verification.intercepted = pin.is_intercepted(&certificate);
+                }
+            }
+            Check::Socks(n) => {
+                if let (Ok(resp), Some(verification)) = (reply.result,
+                                                         verifications.get_mut(&(host, port))) {
+                    let check = &socks_checks[n];
+                    trace!("{} for {}/{} => {:?}", check.tag, host, port, resp);
+                    if check.passes(&resp) {
+                        verification.socks[n] = Some(reply.elapsed);
+                    }
+                }
+            }
+        }
+
+        let completed = match verifications.get_mut(&(host, port)) {
+            Some(verification) => {
+                verification.expired |= expired;
+                verification.pending -= 1;
+                verification.pending == 0
+            }
+            None => false,
+        };
+        if completed {
+            let verification = verifications.remove(&(host, port)).unwrap();
+            let names = identities(host, port);
+            tracker.lock().unwrap().finish(host, port);
+            progress.add_done();
+            // checks cut short tell nothing either way, the others still count
+            if verification.expired {
+                debug!("{}/{} took longer than {}s to verify, some checks are left out.",
+                       host,
+                       port,
+                       opts.deadline.as_secs());
+            }
+            let mut tags = rules.as_ref()
+                .map(|rules| {
+                    rules.iter()
+                        .zip(verification.matched.iter())
+                        .filter(|&(_, matched)| *matched)
+                        .map(|(&(ref tag, _, _), _)| tag.clone())
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or(Vec::new());
+            if verification.tls {
+                tags.push("TLS_PROXY".to_string());
+            }
+            if verification.tampered {
+                tags.push("TAMPERING".to_string());
+            }
+            if verification.intercepted {
+                tags.push("TLS_INTERCEPTION".to_string());
+            }
+            if let Some(ref scheme) = verification.auth {
+                tags.push("AUTH_REQUIRED".to_string());
+                tags.push(format!("AUTH_{}", scheme.to_uppercase()));
+            }
+            for (check, _) in socks_checks.iter()
+                .zip(verification.socks.iter())
+                .filter(|&(_, passed)| passed.is_some()) {
+                if !tags.contains(&check.tag) {
+                    tags.push(check.tag.clone());
+                }
+            }
+            // servers that are not HTTP proxies are timed by SOCKS: by CONNECT if
+            // one went through, or else by the greeting, so that servers taking
+            // only authenticated clients still have a lag to be searched by
+            let lag = if verification.http.is_none() {
+                let passed = socks_checks.iter()
+                    .zip(verification.socks.iter())
+                    .filter_map(|(check, elapsed)| elapsed.map(|x| (check.is_connect(), x)))
+                    .collect::<Vec<_>>();
+                passed.iter()
+                    .filter(|&&(connect, _)| connect)
+                    .map(|&(_, x)| x)
+                    .min()
+                    .or(passed.iter().map(|&(_, x)| x).min())
+            } else {
+                verification.lag
             };
+            info!("{}/{}: {:?}", host, port, tags);
+            if verification.http.is_none() && tags.is_empty() {
+                debug!("{}/{} is not a proxy server.", host, port);
+                for name in names {
+                    let _ = writer.send(Finding::Failed(name, port));
+                }
+                return followups;
+            }
+            let connect_ports = opts.connect_host.as_ref().map(|_| {
+                opts.connect_ports
+                    .iter()
+                    .zip(verification.connect_ports.iter())
+                    .filter(|&(_, allowed)| *allowed)
+                    .map(|(port, _)| *port)
+                    .collect::<Vec<u16>>()
+            });
+            let software = fingerprint::identify(&verification.made, &verification.passed);
+            if let Some((ref software, ref version)) = software {
+                info!("{}/{} runs {} {}.",
+                      host,
+                      port,
+                      software,
+                      version.as_ref().map_or("", |x| x.as_str()));
+            }
+            for name in names {
+                match db_api::ProxyServer::new(&name,
+                                               port,
+                                               lag,
+                                               verification.http,
+                                               Some(tags.clone())) {
+                    Ok(mut server) => {
+                        server.address = Some(host);
+                        server.credentials = verification.credentials.clone();
+                        server.connect_ports = connect_ports.clone();
+                        if let Some((ref software, ref version)) = software {
+                            server.software = Some(software.clone());
+                            server.software_version = version.clone();
+                        }
+                        let _ = writer.send(Finding::Verified(server));
+                    }
+                    Err(e) => {
+                        debug!("error on verifying server {:?}:{:?}: {:?}", host, port, e)
+                    }
+                }
+            }
+            progress.add_verified();
+        }
+        followups
+    },
+                                  || signal::interrupts() > interrupted,
+                                  grace);
+    if let Err(e) = result {
+        error!("scanner stopped unexpectedly: {}", e);
+    }
 
-            if let (Some(total), Some(remain)) = (total_servers, remain_servers) {
-                info!("progress {2:.2}% ({0}/{1}).",
-                      total - remain,
-                      total,
-                      100f64 * ((total - remain) as f64) / (total as f64));
+    // pending results are saved before the final checkpoint
+    drop(writer);
+    let _ = writer_thread.join();
+    let _ = guard_tx.send(true);
+    let _ = guard.join();
+    reporter.stop();
+    let _ = writeln!(io::stderr(), "{}", progress.snapshot().summary());
+}
+
+#[test]
+fn test_scan() {
+    use engine;
+    use openssl::ssl::SslStream;
+    use std::net::{TcpListener, TcpStream};
+
+    // a stand-in server, answering each connection on a thread of its own
+    fn serve<F>(handle: F) -> u16
+        where F: Fn(TcpStream) + Send + Sync + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = Arc::new(handle);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (stream, handle) = (stream.unwrap(), handle.clone());
+                thread::spawn(move || handle(stream));
             }
-            thread::sleep(Duration::new(15, 0));
-            if let Ok(_) = rx.try_recv() {
-                return;
+        });
+        port
+    }
+    // probes send their payload at once, so one read gets all of it
+    fn request<S: Read>(stream: &mut S) -> Vec<u8> {
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).unwrap_or(0);
+        buf[..n].to_vec()
+    }
+    // what httpbin answers through a proxy
+    fn httpbin() -> Vec<u8> {
+        let body = "{\"headers\": {\"Host\": \"127.0.0.1:9\"}}";
+        format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+    }
+    // a stream that gives back the bytes already read from it first
+    struct Replay {
+        head: io::Cursor<Vec<u8>>,
+        stream: TcpStream,
+    }
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            match try!(self.head.read(buf)) {
+                0 => self.stream.read(buf),
+                n => Ok(n),
             }
         }
+    }
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.stream.write(buf)
+        }
+        fn flush(&mut self) -> IoResult<()> {
+            self.stream.flush()
+        }
+    }
+    // the output, kept in memory
+    #[derive(Clone)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    // a proxy asking for Basic auth that takes user:pass only
+    let auth = serve(|mut stream| {
+        let resp = if String::from_utf8_lossy(&request(&mut stream))
+            .contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n") {
+            httpbin()
+        } else {
+            b"HTTP/1.0 407 Proxy Authentication Required\r\n\
+              Proxy-Authenticate: Basic realm=\"proxy\"\r\n\r\n"
+                .to_vec()
+        };
+        let _ = stream.write_all(&resp);
+    });
+    // a proxy that takes HTTP over TLS only
+    let (ctx, _) = engine::self_signed_context();
+    let tls = serve(move |mut stream| {
+        let head = request(&mut stream);
+        // a handshake starts with a record of type 22
+        if head.first() != Some(&22) {
+            let _ = stream.write_all(b"HTTP/1.0 400 Bad Request\r\n\r\n");
+            return;
+        }
+        let replay = Replay {
+            head: io::Cursor::new(head),
+            stream: stream,
+        };
+        if let Ok(mut stream) = SslStream::accept(&ctx, replay) {
+            request(&mut stream);
+            let _ = stream.write_all(&httpbin());
+        }
     });
+    // a SOCKS5 server without auth that grants whatever it is asked for
+    let socks = serve(|mut stream| {
+        let greeting = request(&mut stream);
+        if greeting.len() < 2 || greeting[0] != 5 {
+            return;
+        }
+        let end = cmp::min(2 + greeting[1] as usize, greeting.len());
+        if !greeting[2..end].contains(&0) {
+            let _ = stream.write_all(&[5, 0xff]);
+            return;
+        }
+        let mut reply = vec![5, 0];
+        if greeting[end..].starts_with(&[5, 1]) {
+            reply.extend_from_slice(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 9]);
+        }
+        let _ = stream.write_all(&reply);
+    });
+    // a proxy that answers httpbin right away and nothing else in time
+    let staller = serve(|mut stream| {
+        if !request(&mut stream).starts_with(b"GET http://127.0.0.1:9/headers ") {
+            thread::sleep(Duration::new(4, 0));
+        }
+        let _ = stream.write_all(&httpbin());
+    });
+
+    let written = Written(Arc::new(Mutex::new(Vec::new())));
+    let mut output = output::Output::new(Box::new(written.clone()), output::Format::Json);
+    output.include_credentials(true);
+    let opts = ScanOptions {
+        reference: "127.0.0.1:9".to_string(),
+        httpbin: "127.0.0.1:9".to_string(),
+        // enough for every check to run at once
+        num_workers: 64,
+        num_sweepers: Some(4),
+        connect_timeout: Duration::new(2, 0),
+        timeout: Duration::new(8, 0),
+        deadline: Duration::new(2, 0),
+        max_response: 65536,
+        rate: None,
+        per_host_rate: None,
+        egress: Egress::new(Vec::new(), Vec::new()),
+        credentials: vec!["guest:guest".to_string(), "user:pass".to_string()],
+        tamper: None,
+        pin: None,
+        connect_host: None,
+        connect_ports: Vec::new(),
+        gateway: Vec::new(),
+    };
+    let servers = vec![auth, tls, socks, staller]
+        .into_iter()
+        .map(|port| (Ipv4Addr::new(127, 0, 0, 1), port))
+        .collect::<Vec<_>>();
+    {
+        let _lock = signal::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        scan(None,
+             Some(output),
+             servers.into_iter(),
+             HashMap::new(),
+             HashMap::new(),
+             opts,
+             None);
+    }
+
+    let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+    let mut found = HashMap::new();
+    for line in written.lines() {
+        let data: Value = serde_json::from_str(line).unwrap();
+        found.insert(data.find("port").and_then(|x| x.as_u64()).unwrap() as u16, data);
+    }
+    assert_eq!(found.len(), 4);
+    let tags = |port| {
+        found[&port]
+            .find("tags")
+            .and_then(|x| x.as_array())
+            .map_or(Vec::new(), |x| x.iter().filter_map(|x| x.as_str()).collect::<Vec<_>>())
+    };
+
+    let credentials = found[&auth].find("credentials").and_then(|x| x.as_str());
+    assert_eq!(credentials, Some("user:pass"));
+    assert!(found[&auth].find("anonymity").is_some());
+    assert!(tags(auth).contains(&"AUTH_REQUIRED") && tags(auth).contains(&"AUTH_BASIC"));
 
-    while let Some(worker) = workers.pop() {
-        let _ = worker.join();
+    assert!(found[&tls].find("anonymity").is_some());
+    assert!(tags(tls).contains(&"TLS_PROXY"));
+
+    // not an HTTP proxy, but a proxy all the same
+    assert!(found[&socks].find("anonymity").is_none());
+    for tag in &["SOCKS5", "SOCKS5_AUTH_NONE", "SOCKS5_CONNECT_IPV4", "SOCKS5_CONNECT_DOMAIN"] {
+        assert!(tags(socks).contains(tag), "{} missing", tag);
     }
-    tx.send(true).unwrap();
-    let _ = monitor.join();
+    assert!(!tags(socks).contains(&"SOCKS5_AUTH_USERPASS") && !tags(socks).contains(&"SOCKS4"));
+
+    // checks cut short by the deadline are left out, the server is kept
+    assert!(found[&staller].find("anonymity").is_some());
+    assert!(found[&staller].find("lag").is_none());
+    assert!(tags(staller).is_empty());
 }
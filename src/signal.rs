@@ -0,0 +1,31 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 18 Oct, 2016
+
+use libc;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+#[cfg(test)]
+use std::sync::Mutex;
+
+static INTERRUPTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// tests that send signals and those that stop on them take turns
+#[cfg(test)]
+lazy_static! {
+    pub static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Counts SIGINTs and SIGTERMs instead of letting them terminate the process.
+pub fn catch_interrupt() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_interrupt as libc::sighandler_t);
+    }
+}
+
+/// Returns how many SIGINTs and SIGTERMs have been received so far.
+pub fn interrupts() -> usize {
+    INTERRUPTS.load(Ordering::SeqCst)
+}
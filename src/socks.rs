@@ -0,0 +1,201 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use std::net::{SocketAddr, SocketAddrV4};
+
+const SOCKS4_VERSION: u8 = 4;
+const SOCKS4_CONNECT: u8 = 1;
+const SOCKS4_GRANTED: u8 = 0x5a;
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_CONNECT: u8 = 1;
+const SOCKS5_SUCCEEDED: u8 = 0;
+const SOCKS5_ATYP_IPV4: u8 = 1;
+const SOCKS5_ATYP_DOMAIN: u8 = 3;
+const SOCKS5_ATYP_IPV6: u8 = 4;
+
+pub const SOCKS5_NO_AUTH: u8 = 0;
+pub const SOCKS5_GSSAPI: u8 = 1;
+pub const SOCKS5_USERPASS: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Expect {
+    Socks4Granted,
+    Socks5,
+    Socks5Method(u8),
+    Socks5Connected,
+}
+
+/// A request sent to a server being verified and the reply that earns it
+/// `tag`.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub tag: String,
+    pub payload: Vec<u8>,
+    expect: Expect,
+}
+
+impl Check {
+    pub fn socks4(tag: &str, payload: Vec<u8>) -> Check {
+        Check::new(tag, payload, Expect::Socks4Granted)
+    }
+
+    /// Offers every auth method; any SOCKS5 reply will do.
+    pub fn socks5(tag: &str) -> Check {
+        let payload = socks5_greeting(&[SOCKS5_NO_AUTH, SOCKS5_GSSAPI, SOCKS5_USERPASS]);
+        Check::new(tag, payload, Expect::Socks5)
+    }
+
+    /// Offers `method` alone, so the server accepts it only if it supports it.
+    pub fn socks5_method(tag: &str, method: u8) -> Check {
+        Check::new(tag, socks5_greeting(&[method]), Expect::Socks5Method(method))
+    }
+
+    /// `payload` follows a greeting offering no authentication.
+    pub fn socks5_connect(tag: &str, payload: Vec<u8>) -> Check {
+        let mut greeting = socks5_greeting(&[SOCKS5_NO_AUTH]);
+        greeting.extend_from_slice(&payload);
+        Check::new(tag, greeting, Expect::Socks5Connected)
+    }
+
+    fn new(tag: &str, payload: Vec<u8>, expect: Expect) -> Check {
+        Check {
+            tag: tag.to_string(),
+            payload: payload,
+            expect: expect,
+        }
+    }
+
+    /// Whether the server has connected to a destination for us.
+    pub fn is_connect(&self) -> bool {
+        self.expect == Expect::Socks4Granted || self.expect == Expect::Socks5Connected
+    }
+
+    pub fn passes(&self, reply: &[u8]) -> bool {
+        match self.expect {
+            Expect::Socks4Granted => socks4_granted(reply),
+            Expect::Socks5 => reply.len() >= 2 && reply[0] == SOCKS5_VERSION,
+            Expect::Socks5Method(method) => socks5_accepts(reply, method),
+            Expect::Socks5Connected => socks5_connected(reply),
+        }
+    }
+}
+
+/// A SOCKS4 request to connect to `addr`.
+pub fn socks4_connect(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut request = vec![SOCKS4_VERSION, SOCKS4_CONNECT];
+    request.push((addr.port() >> 8) as u8);
+    request.push(addr.port() as u8);
+    request.extend_from_slice(&addr.ip().octets());
+    // empty user id
+    request.push(0);
+    request
+}
+
+/// A SOCKS4a request to connect to `host`, which the server resolves.
+pub fn socks4a_connect(host: &str, port: u16) -> Vec<u8> {
+    let mut request = vec![SOCKS4_VERSION, SOCKS4_CONNECT];
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    // 0.0.0.x tells the server a hostname follows the user id
+    request.extend_from_slice(&[0, 0, 0, 1]);
+    request.push(0);
+    request.extend_from_slice(host.as_bytes());
+    request.push(0);
+    request
+}
+
+/// Whether `reply` starts with a SOCKS4 reply granting the request.
+pub fn socks4_granted(reply: &[u8]) -> bool {
+    reply.len() >= 8 && reply[0] == 0 && reply[1] == SOCKS4_GRANTED
+}
+
+/// Offers `methods` to authenticate with.
+pub fn socks5_greeting(methods: &[u8]) -> Vec<u8> {
+    let mut greeting = vec![SOCKS5_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    greeting
+}
+
+/// A SOCKS5 request to connect to `addr`, either IPv4 or IPv6.
+pub fn socks5_connect(addr: &SocketAddr) -> Vec<u8> {
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CONNECT, 0];
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(ref addr) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            for segment in &addr.ip().segments() {
+                request.push((segment >> 8) as u8);
+                request.push(*segment as u8);
+            }
+        }
+    }
+    request.push((addr.port() >> 8) as u8);
+    request.push(addr.port() as u8);
+    request
+}
+
+/// A SOCKS5 request to connect to `host`, which the server resolves.
+pub fn socks5_connect_domain(host: &str, port: u16) -> Vec<u8> {
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CONNECT, 0, SOCKS5_ATYP_DOMAIN];
+    request.push(host.len() as u8);
+    request.extend_from_slice(host.as_bytes());
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    request
+}
+
+/// Whether the server picked `method` in answer to a greeting.
+pub fn socks5_accepts(reply: &[u8], method: u8) -> bool {
+    reply.len() >= 2 && reply[0] == SOCKS5_VERSION && reply[1] == method
+}
+
+/// Whether the server has accepted no authentication and then connected.
+pub fn socks5_connected(reply: &[u8]) -> bool {
+    socks5_accepts(reply, SOCKS5_NO_AUTH) && reply.len() >= 4 && reply[2] == SOCKS5_VERSION &&
+    reply[3] == SOCKS5_SUCCEEDED
+}
+
+#[test]
+fn test_socks4() {
+    use std::net::Ipv4Addr;
+
+    let addr = SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 80);
+    assert_eq!(socks4_connect(&addr), [4, 1, 0, 80, 93, 184, 216, 34, 0]);
+    assert_eq!(socks4a_connect("a.cn", 443),
+               [4, 1, 1, 187, 0, 0, 0, 1, 0, b'a', b'.', b'c', b'n', 0]);
+    assert!(socks4_granted(&[0, 0x5a, 0, 80, 93, 184, 216, 34, b'H']));
+    assert!(!socks4_granted(&[0, 0x5b, 0, 0, 0, 0, 0, 0]));
+    assert!(!socks4_granted(b"HTTP/1.0 400 Bad Request\r\n\r\n"));
+}
+
+#[test]
+fn test_socks5() {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 80));
+    assert_eq!(socks5_connect(&addr), [5, 1, 0, 1, 93, 184, 216, 34, 0, 80]);
+    let addr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+                                                443,
+                                                0,
+                                                0));
+    assert_eq!(socks5_connect(&addr),
+               [5, 1, 0, 4, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 187]);
+    assert_eq!(socks5_connect_domain("a.cn", 80),
+               [5, 1, 0, 3, 4, b'a', b'.', b'c', b'n', 0, 80]);
+
+    let check = Check::socks5_method("SOCKS5_AUTH_USERPASS", SOCKS5_USERPASS);
+    assert_eq!(check.payload, [5, 1, 2]);
+    assert!(check.passes(&[5, 2]));
+    assert!(!check.passes(&[5, 0xff]));
+    assert!(Check::socks5("SOCKS5").passes(&[5, 0xff]));
+
+    let check = Check::socks5_connect("SOCKS5_CONNECT_DOMAIN", socks5_connect_domain("a.cn", 80));
+    assert_eq!(&check.payload[..3], [5, 1, 0]);
+    assert!(check.is_connect());
+    assert!(check.passes(&[5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]));
+    assert!(!check.passes(&[5, 0, 5, 4, 0, 1, 0, 0, 0, 0, 0, 0]));
+    assert!(!check.passes(&[5, 2]));
+}
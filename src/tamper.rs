@@ -0,0 +1,267 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 19 Oct, 2016
+
+use openssl::crypto::hash::{self, Type};
+
+use http::Response;
+
+// headers that change the content or what is done with it, unlike those
+// caches add; the body length is compared on its own
+fn is_content_header(name: &str) -> bool {
+    let name = name.to_lowercase();
+    (name.starts_with("content-") && name != "content-length") ||
+    ["set-cookie", "location", "refresh"].contains(&name.as_str())
+}
+
+/// Content fetched through a proxy to see whether the proxy changes it.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    sha256: Option<Vec<u8>>,
+    length: Option<usize>,
+    // names of the headers the content is served with, once seen directly
+    headers: Option<Vec<String>>,
+}
+
+impl Reference {
+    /// `url` is a plain HTTP one, e.g. http://example.com:8080/index.html;
+    /// `sha256` is given in hex.
+    pub fn new(url: &str,
+               sha256: Option<&str>,
+               length: Option<usize>)
+               -> Result<Reference, String> {
+        let url = url.trim_left_matches("http://");
+        let (authority, path) = match url.find('/') {
+            Some(n) => (&url[..n], &url[n..]),
+            None => (url, "/"),
+        };
+        let mut parts = authority.splitn(2, ':');
+        let host = parts.next().unwrap_or("");
+        let port = match parts.next().map(|x| x.parse::<u16>()) {
+            Some(Ok(port)) => port,
+            Some(Err(_)) => return Err(format!("invalid port in {}", authority)),
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(format!("no host in {}", url));
+        }
+        let sha256 = match sha256 {
+            Some(x) => Some(try!(parse_sha256(x).ok_or(format!("invalid sha256 {}", x)))),
+            None => None,
+        };
+        Ok(Reference {
+            host: host.to_string(),
+            port: port,
+            path: path.to_string(),
+            sha256: sha256,
+            length: length,
+            headers: None,
+        })
+    }
+
+    /// Takes what is not given yet from the content as fetched directly.
+    pub fn learn(&mut self, resp: &Response) {
+        if self.sha256.is_none() && self.length.is_none() {
+            self.sha256 = Some(hash::hash(Type::SHA256, &resp.body));
+        }
+        self.headers = Some(resp.headers
+            .iter()
+            .map(|&(ref name, _)| name.to_lowercase())
+            .collect());
+    }
+
+    /// Whether there is anything to compare what proxies return with.
+    pub fn is_known(&self) -> bool {
+        self.sha256.is_some() || self.length.is_some()
+    }
+
+    /// A request for the content through a proxy, or directly if not `proxied`.
+    pub fn request(&self, proxied: bool) -> String {
+        let authority = if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        let target = if proxied {
+            format!("http://{}{}", authority, self.path)
+        } else {
+            self.path.clone()
+        };
+        format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", target, authority)
+    }
+
+    /// Whether a proxy has returned the content with its body changed or
+    /// content headers added. Responses other than the content tell nothing.
+    pub fn is_tampered(&self, resp: &Response) -> bool {
+        if !resp.is_success() {
+            return false;
+        }
+        if let Some(ref sha256) = self.sha256 {
+            if hash::hash(Type::SHA256, &resp.body) != *sha256 {
+                return true;
+            }
+        }
+        if let Some(length) = self.length {
+            if resp.body.len() != length {
+                return true;
+            }
+        }
+        match self.headers {
+            Some(ref headers) => {
+                resp.headers.iter().any(|&(ref name, _)| {
+                    is_content_header(name) && !headers.contains(&name.to_lowercase())
+                })
+            }
+            None => false,
+        }
+    }
+}
+
+/// A TLS server reached through proxies and the certificate it presents,
+/// to see whether proxies intercept TLS.
+#[derive(Debug, Clone)]
+pub struct Pin {
+    pub host: String,
+    pub port: u16,
+    sha256: Option<Vec<u8>>,
+}
+
+impl Pin {
+    /// `spec` is host:port, on 443 unless given; `sha256` is the fingerprint
+    /// of the certificate in hex, with or without colons.
+    pub fn new(spec: &str, sha256: Option<&str>) -> Result<Pin, String> {
+        let mut parts = spec.splitn(2, ':');
+        let host = parts.next().unwrap_or("");
+        let port = match parts.next().map(|x| x.parse::<u16>()) {
+            Some(Ok(port)) => port,
+            Some(Err(_)) => return Err(format!("invalid port in {}", spec)),
+            None => 443,
+        };
+        if host.is_empty() {
+            return Err(format!("no host in {}", spec));
+        }
+        let sha256 = match sha256 {
+            Some(x) => {
+                Some(try!(parse_sha256(&x.replace(":", ""))
+                    .ok_or(format!("invalid sha256 {}", x))))
+            }
+            None => None,
+        };
+        Ok(Pin {
+            host: host.to_string(),
+            port: port,
+            sha256: sha256,
+        })
+    }
+
+    /// Takes the fingerprint of the certificate as presented directly, unless
+    /// one is given.
+    pub fn learn(&mut self, sha256: Vec<u8>) {
+        if self.sha256.is_none() {
+            self.sha256 = Some(sha256);
+        }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.sha256.is_some()
+    }
+
+    /// The request opening a tunnel to the server through a proxy.
+    pub fn tunnel_request(&self) -> String {
+        format!("CONNECT {host}:{port} HTTP/1.0\r\nHost: {host}:{port}\r\n\r\n",
+                host = self.host,
+                port = self.port)
+    }
+
+    /// A request to send over TLS, which is what gets the handshake done.
+    pub fn request(&self) -> String {
+        format!("HEAD / HTTP/1.0\r\nHost: {}\r\n\r\n", self.host)
+    }
+
+    /// Whether a certificate presented through a proxy is not the one pinned.
+    pub fn is_intercepted(&self, sha256: &[u8]) -> bool {
+        self.sha256.as_ref().map_or(false, |x| x.as_slice() != sha256)
+    }
+}
+
+// a sha256 digest in hex, which is 32 bytes long
+fn parse_sha256(s: &str) -> Option<Vec<u8>> {
+    parse_hex(s).and_then(|x| if x.len() == 32 { Some(x) } else { None })
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    (0..s.len() / 2).map(|n| u8::from_str_radix(&s[2 * n..2 * n + 2], 16).ok()).collect()
+}
+
+#[test]
+fn test_tamper() {
+    let page = Response::parse(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+                                 Content-Length: 5\r\n\r\nhello")
+        .unwrap();
+    let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    let reference = Reference::new("http://example.com:8080/a.txt", Some(sha256), None).unwrap();
+    assert_eq!(reference.request(true),
+               "GET http://example.com:8080/a.txt HTTP/1.0\r\nHost: example.com:8080\r\n\r\n");
+    assert_eq!(reference.request(false),
+               "GET /a.txt HTTP/1.0\r\nHost: example.com:8080\r\n\r\n");
+    assert!(!reference.is_tampered(&page));
+    let injected = Response::parse(b"HTTP/1.1 200 OK\r\n\r\nhello<script>ad()</script>").unwrap();
+    assert!(reference.is_tampered(&injected));
+    let blocked = Response::parse(b"HTTP/1.1 403 Forbidden\r\n\r\n").unwrap();
+    assert!(!reference.is_tampered(&blocked));
+
+    // the body is learnt from a direct fetch when not given
+    let mut reference = Reference::new("example.com", None, None).unwrap();
+    assert!(!reference.is_known());
+    reference.learn(&page);
+    assert!(reference.is_known());
+    let cached = Response::parse(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\
+                                   Via: 1.1 squid\r\nX-Cache: HIT\r\nCache-Control: max-age=60\r\n\
+                                   Expires: Wed, 19 Oct 2016 08:00:00 GMT\r\n\r\nhello")
+        .unwrap();
+    assert!(!reference.is_tampered(&cached));
+    let injected = Response::parse(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\
+                                     Set-Cookie: ad=1\r\n\r\nhello")
+        .unwrap();
+    assert!(reference.is_tampered(&injected));
+
+    assert!(Reference::new("http://example.com/", None, Some(5)).unwrap().is_known());
+    assert!(Reference::new("http://example.com/", Some("xyz"), None).is_err());
+    // a sha1 digest is no good
+    assert!(Reference::new("http://example.com/",
+                           Some("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"),
+                           None)
+        .is_err());
+    assert!(Reference::new("http://:80/", None, None).is_err());
+}
+
+#[test]
+fn test_pin() {
+    let mut pin = Pin::new("example.com", None).unwrap();
+    assert_eq!(pin.port, 443);
+    assert_eq!(pin.tunnel_request(),
+               "CONNECT example.com:443 HTTP/1.0\r\nHost: example.com:443\r\n\r\n");
+    assert!(!pin.is_known());
+    assert!(!pin.is_intercepted(&[1, 2]));
+    pin.learn(vec![0xab, 0xcd]);
+    assert!(!pin.is_intercepted(&[0xab, 0xcd]));
+    assert!(pin.is_intercepted(&[0xab, 0xce]));
+
+    let sha256 = "AB:CD:AB:CD:AB:CD:AB:CD:AB:CD:AB:CD:AB:CD:AB:CD:\
+                  AB:CD:AB:CD:AB:CD:AB:CD:AB:CD:AB:CD:AB:CD:AB:CD";
+    let pin = Pin::new("127.0.0.1:8443", Some(sha256)).unwrap();
+    assert_eq!(pin.port, 8443);
+    let pinned = [0xab, 0xcd].iter().cycle().take(32).cloned().collect::<Vec<u8>>();
+    assert!(!pin.is_intercepted(&pinned));
+    assert!(pin.is_intercepted(&[0xab, 0xcd]));
+    assert!(Pin::new("example.com:https", None).is_err());
+    assert!(Pin::new("example.com", Some("AB:C")).is_err());
+    // what openssl x509 -fingerprint gives without -sha256
+    assert!(Pin::new("example.com",
+                     Some("A9:4A:8F:E5:CC:B1:9B:A6:1C:4C:08:73:D3:91:E9:87:98:2F:BB:D3"))
+        .is_err());
+}
@@ -0,0 +1,276 @@
+// Jianing Yang <jianingy.yang@gmail.com> @ 17 Oct, 2016
+
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use iprange::{Ipv4Network, Ipv4RangeSet};
+use ports;
+use resolver;
+
+error_chain! {
+
+    errors {
+        InvalidTarget(t: String) {
+            description("invalid target")
+            display("invalid target: {}", t)
+        }
+    }
+
+}
+
+const ROUNDS: usize = 4;
+
+/// A target as given on the command line or in a targets file.
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    // a network or a single host, e.g. 10.0.0.0/24 or 10.0.0.1
+    Network(Ipv4Network),
+    // a range of addresses, e.g. 10.0.0.10-10.0.0.20
+    Range(u32, u32),
+    // a host with its own ports, e.g. 10.0.0.1:8080 or 10.0.0.1:8000-8010
+    Service(Ipv4Addr, Vec<u16>),
+    // a hostname to be resolved, with or without its own ports
+    Name(String, Option<Vec<u16>>),
+}
+
+impl Target {
+    pub fn from_str(s: &str) -> Result<Target> {
+        let invalid = || ErrorKind::InvalidTarget(s.to_string());
+        let s = s.trim();
+        if let Some(n) = s.find(':') {
+            let ports = try!(ports::parse(&s[n + 1..]).chain_err(&invalid));
+            if resolver::is_hostname(&s[..n]) {
+                return Ok(Target::Name(s[..n].to_string(), Some(ports)));
+            }
+            let host = try!(Ipv4Addr::from_str(&s[..n]).chain_err(&invalid));
+            return Ok(Target::Service(host, ports));
+        }
+        if resolver::is_hostname(s) {
+            return Ok(Target::Name(s.to_string(), None));
+        }
+        if let Some(n) = s.find('-') {
+            let first = try!(Ipv4Addr::from_str(s[..n].trim()).chain_err(&invalid));
+            let last = try!(Ipv4Addr::from_str(s[n + 1..].trim()).chain_err(&invalid));
+            let (first, last) = (u32::from(first), u32::from(last));
+            if first > last {
+                return Err(invalid().into());
+            }
+            return Ok(Target::Range(first, last));
+        }
+        Ok(Target::Network(try!(Ipv4Network::from_str(s).chain_err(&invalid))))
+    }
+}
+
+// splitmix64 finalizer
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Every (ip, port) pair of a scan, addressable by an index in `0..len()`:
+/// each host of `hosts` on each of `ports`, followed by the `services` that
+/// are not already among them.
+#[derive(Debug)]
+pub struct TargetSpace {
+    ranges: Vec<(u32, u32)>,
+    // # of hosts in the ranges before each range
+    offsets: Vec<u64>,
+    ports: Vec<u16>,
+    num_hosts: u64,
+    services: Vec<(u32, u16)>,
+}
+
+impl TargetSpace {
+    pub fn new(hosts: &Ipv4RangeSet, ports: Vec<u16>, services: &[(u32, u16)]) -> TargetSpace {
+        let mut offsets = Vec::with_capacity(hosts.ranges().len());
+        let mut num_hosts = 0;
+        for &(first, last) in hosts.ranges() {
+            offsets.push(num_hosts);
+            num_hosts += (last - first) as u64 + 1;
+        }
+        let mut services = services.iter()
+            .filter(|&&(ip, port)| !(hosts.contains(Ipv4Addr::from(ip)) && ports.contains(&port)))
+            .cloned()
+            .collect::<Vec<(u32, u16)>>();
+        services.sort();
+        services.dedup();
+        TargetSpace {
+            ranges: hosts.ranges().to_vec(),
+            offsets: offsets,
+            ports: ports,
+            num_hosts: num_hosts,
+            services: services,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.num_hosts * self.ports.len() as u64 + self.services.len() as u64
+    }
+
+    pub fn get(&self, index: u64) -> (Ipv4Addr, u16) {
+        let num_ports = self.ports.len() as u64;
+        if index >= self.num_hosts * num_ports {
+            let (ip, port) = self.services[(index - self.num_hosts * num_ports) as usize];
+            return (Ipv4Addr::from(ip), port);
+        }
+        let (host, port) = (index / num_ports, index % num_ports);
+        let n = match self.offsets.binary_search(&host) {
+            Ok(n) => n,
+            Err(n) => n - 1,
+        };
+        let ip = self.ranges[n].0 + (host - self.offsets[n]) as u32;
+        (Ipv4Addr::from(ip), self.ports[port as usize])
+    }
+}
+
+/// Visits `0..len` in a pseudo-random order determined by `seed`. The order
+/// comes from a Feistel network over the smallest even power of two covering
+/// `len`; outputs beyond `len` are skipped, so nothing is ever materialised.
+#[derive(Debug)]
+pub struct Permutation {
+    len: u64,
+    domain: u64,
+    half_bits: u32,
+    keys: [u64; ROUNDS],
+    next: u64,
+    emitted: u64,
+}
+
+impl Permutation {
+    pub fn new(len: u64, seed: u64) -> Permutation {
+        let mut bits = 0;
+        while (1u64 << bits) < len {
+            bits += 1;
+        }
+        let half_bits = if bits < 2 { 1 } else { (bits + 1) / 2 };
+        let mut keys = [0; ROUNDS];
+        for (n, key) in keys.iter_mut().enumerate() {
+            *key = mix(seed.wrapping_add(n as u64));
+        }
+        Permutation {
+            len: len,
+            domain: 1 << (half_bits * 2),
+            half_bits: half_bits,
+            keys: keys,
+            next: 0,
+            emitted: 0,
+        }
+    }
+
+    fn permute(&self, x: u64) -> u64 {
+        let mask = (1 << self.half_bits) - 1;
+        let (mut left, mut right) = (x >> self.half_bits, x & mask);
+        for key in self.keys.iter() {
+            let round = left ^ (mix(right ^ key) & mask);
+            left = right;
+            right = round;
+        }
+        (left << self.half_bits) | right
+    }
+}
+
+impl Iterator for Permutation {
+    type Item = u64;
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remain = (self.len - self.emitted) as usize;
+        (remain, Some(remain))
+    }
+
+    fn next(&mut self) -> Option<u64> {
+        while self.next < self.domain {
+            let x = self.permute(self.next);
+            self.next += 1;
+            if x < self.len {
+                self.emitted += 1;
+                return Some(x);
+            }
+        }
+        None
+    }
+}
+
+/// The targets of a `TargetSpace` in the order of a seeded `Permutation`.
+#[derive(Debug)]
+pub struct ShuffledTargets {
+    space: TargetSpace,
+    permutation: Permutation,
+}
+
+impl ShuffledTargets {
+    pub fn new(space: TargetSpace, seed: u64) -> ShuffledTargets {
+        let permutation = Permutation::new(space.len(), seed);
+        ShuffledTargets {
+            space: space,
+            permutation: permutation,
+        }
+    }
+}
+
+impl Iterator for ShuffledTargets {
+    type Item = (Ipv4Addr, u16);
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.permutation.size_hint()
+    }
+
+    fn next(&mut self) -> Option<(Ipv4Addr, u16)> {
+        self.permutation.next().map(|x| self.space.get(x))
+    }
+}
+
+#[test]
+fn test_target_space() {
+    let mut hosts = Ipv4RangeSet::new();
+    hosts.insert(u32::from(Ipv4Addr::new(10, 0, 0, 1)), u32::from(Ipv4Addr::new(10, 0, 0, 2)));
+    hosts.insert(u32::from(Ipv4Addr::new(10, 0, 1, 0)), u32::from(Ipv4Addr::new(10, 0, 1, 0)));
+    let services = [(u32::from(Ipv4Addr::new(10, 0, 0, 9)), 3128),
+                    (u32::from(Ipv4Addr::new(10, 0, 0, 1)), 80),
+                    (u32::from(Ipv4Addr::new(10, 0, 0, 9)), 3128)];
+    let space = TargetSpace::new(&hosts, vec![80, 8080], &services);
+    assert_eq!(space.len(), 7);
+    let targets = (0..space.len()).map(|x| space.get(x)).collect::<Vec<_>>();
+    assert_eq!(targets,
+               [(Ipv4Addr::new(10, 0, 0, 1), 80),
+                (Ipv4Addr::new(10, 0, 0, 1), 8080),
+                (Ipv4Addr::new(10, 0, 0, 2), 80),
+                (Ipv4Addr::new(10, 0, 0, 2), 8080),
+                (Ipv4Addr::new(10, 0, 1, 0), 80),
+                (Ipv4Addr::new(10, 0, 1, 0), 8080),
+                (Ipv4Addr::new(10, 0, 0, 9), 3128)]);
+}
+
+#[test]
+fn test_parse_target() {
+    assert_eq!(Target::from_str("10.0.0.0/24").unwrap(),
+               Target::Network(Ipv4Network::from_str("10.0.0.0/24").unwrap()));
+    assert_eq!(Target::from_str("10.0.0.1 - 10.0.1.0").unwrap(),
+               Target::Range(u32::from(Ipv4Addr::new(10, 0, 0, 1)),
+                             u32::from(Ipv4Addr::new(10, 0, 1, 0))));
+    assert_eq!(Target::from_str("10.0.0.1:8080,3128").unwrap(),
+               Target::Service(Ipv4Addr::new(10, 0, 0, 1), vec![3128, 8080]));
+    assert_eq!(Target::from_str("proxy.example.net:3128").unwrap(),
+               Target::Name("proxy.example.net".to_string(), Some(vec![3128])));
+    assert_eq!(Target::from_str("proxy.example.net").unwrap(),
+               Target::Name("proxy.example.net".to_string(), None));
+    assert!(Target::from_str("10.0.1.0-10.0.0.1").is_err());
+    assert!(Target::from_str("10.0.0.1:http").is_err());
+    assert!(Target::from_str("10.0.0.300").is_err());
+}
+
+#[test]
+fn test_permutation() {
+    for &len in [0u64, 1, 2, 3, 17, 1000, 4096].iter() {
+        let mut visited = Permutation::new(len, 42).collect::<Vec<u64>>();
+        visited.sort();
+        assert_eq!(visited, (0..len).collect::<Vec<u64>>());
+    }
+    let a = Permutation::new(1000, 1).collect::<Vec<u64>>();
+    let b = Permutation::new(1000, 1).collect::<Vec<u64>>();
+    let c = Permutation::new(1000, 2).collect::<Vec<u64>>();
+    assert_eq!(a, b);
+    assert!(a != c);
+    assert!(a != (0..1000).collect::<Vec<u64>>());
+}